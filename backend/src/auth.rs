@@ -0,0 +1,125 @@
+use std::{ convert::Infallible, env, net::{ IpAddr, SocketAddr }, sync::LazyLock };
+use axum::{
+    extract::{ ConnectInfo, FromRequestParts },
+    http::{ header, request::Parts, HeaderMap },
+    response::{ IntoResponse, Response },
+    Json,
+};
+use serde_json::json;
+use sha2::{ Digest, Sha256 };
+use subtle::ConstantTimeEq;
+use crate::types::StatusCodes;
+
+/// Who is making a request. The client generates a stable id and sends it as
+/// `X-User-Id`; requests without a usable id are anonymous. `ip` is the client
+/// address as seen through any trusted proxies.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub user: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Secret salt for `Identity::ip_hash`. Without it the hash of an address could be
+/// reversed by hashing every IPv4 address, so the server refuses to start.
+pub static IP_HASH_SALT: LazyLock<String> = LazyLock::new(|| {
+    env::var("IP_HASH_SALT")
+        .ok()
+        .filter(|salt| !salt.trim().is_empty())
+        .expect("IP_HASH_SALT must be set")
+});
+
+impl Identity {
+    /// Salted hash of the client address, so lessons can be counted and addresses
+    /// banned without storing the address itself. The hash is never sent to clients.
+    pub fn ip_hash(&self) -> Option<String> {
+        let ip = self.ip?;
+        Some(format!("{:x}", Sha256::digest(format!("{}{}", *IP_HASH_SALT, ip))))
+    }
+
+    /// Identity of a request whose user id was already read from wherever the
+    /// transport carries it, such as a socket event payload.
+    pub fn from_parts(user: Option<String>, parts: &Parts) -> Self {
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip());
+        Identity { user, ip: peer.map(|peer| client_ip(peer, &parts.headers)) }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Identity {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts.headers
+            .get("x-user-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_user_id);
+        Ok(Identity::from_parts(user, parts))
+    }
+}
+
+/// Proxies allowed to report the client address in `X-Forwarded-For`, from the
+/// comma separated `TRUSTED_PROXIES`.
+static TRUSTED_PROXIES: LazyLock<Vec<IpAddr>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+});
+
+/// Walks `X-Forwarded-For` from the right, skipping trusted proxies, and returns the
+/// first address that was not added by one. Headers from untrusted peers are ignored
+/// since anyone can set them.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !TRUSTED_PROXIES.contains(&peer) {
+        return peer;
+    }
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !TRUSTED_PROXIES.contains(ip))
+        .or(forwarded.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+/// Accepts ids of up to 64 letters, digits, `-` and `_`.
+pub fn parse_user_id(id: &str) -> Option<String> {
+    let id = id.trim();
+    let valid =
+        !id.is_empty() &&
+        id.len() <= 64 &&
+        id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| id.to_string())
+}
+
+/// Guard for admin routes: requires `Authorization: Bearer <ADMIN_TOKEN>`. Admin
+/// routes are disabled entirely when `ADMIN_TOKEN` is not set.
+#[derive(Debug, Clone, Copy)]
+pub struct Admin;
+
+impl<S: Send + Sync> FromRequestParts<S> for Admin {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let expected = env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        let provided = parts.headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        // Compares digests in constant time so the token cannot be guessed from timings
+        let matches = |expected: &str, provided: &str| {
+            bool::from(Sha256::digest(expected).ct_eq(&Sha256::digest(provided)))
+        };
+        match (expected, provided) {
+            (Some(expected), Some(provided)) if matches(&expected, provided) => Ok(Admin),
+            _ => Err(Json(json!({"status": StatusCodes::Unauthorized})).into_response()),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::LazyLock;
+use mongodb::bson::{ self, doc, oid::ObjectId, DateTime };
+use serde_json::json;
+use tracing::info;
+use crate::{
+    auth::Identity,
+    llm::{ Llm, Stage },
+    moderation::{ Subject, MODERATION },
+    quota::QUOTAS,
+    ratelimit::{ Limit, RateLimitLayer },
+    types::{ ChatMessage, ChatRole, LessonRecord, Question, StatusCodes },
+    utils::{ fetch_wikipedia_content, record_usage, truncate_content, Collections },
+};
+
+const MAX_QUESTION_CHARS: usize = 1000;
+/// Earlier messages sent back to the model with each question.
+const HISTORY_MESSAGES: usize = 10;
+/// Messages kept per conversation; older ones are dropped.
+const STORED_MESSAGES: i32 = 200;
+const WIKIPEDIA_CONTEXT_CHARS: usize = 6000;
+
+/// Shared by `POST /lessons/{id}/questions` and the `ask_question` socket event, so
+/// switching transports does not reset a client's allowance.
+pub static QUESTION_LIMIT: LazyLock<RateLimitLayer> = LazyLock::new(||
+    RateLimitLayer::new(Limit::from_env("QUESTIONS", 10))
+);
+
+/// Answers `question` about lesson `lesson_id` using the lesson and its Wikipedia
+/// article as context, passing each piece of the answer to `on_token` as it streams
+/// in. Questions from identified users are stored with their answers and earlier
+/// ones are used as context. Answers are charged to the lesson's token budget. Both
+/// the question and the answer are screened, and either being blocked rejects the
+/// question.
+pub async fn answer_question(
+    lesson_id: &str,
+    identity: &Identity,
+    question: Question,
+    collections: &Collections,
+    mut on_token: impl FnMut(&str)
+) -> Result<ChatMessage, StatusCodes> {
+    let text = question.question.trim();
+    if text.is_empty() || text.chars().count() > MAX_QUESTION_CHARS {
+        return Err(StatusCodes::InvalidData);
+    }
+    let oid = ObjectId::parse_str(lesson_id).map_err(|_| StatusCodes::InvalidID)?;
+    let lesson = collections.lessons
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::LessonNotFound)?;
+    let step = match question.step {
+        Some(index) => {
+            let step = lesson.outline.get(index as usize).ok_or(StatusCodes::InvalidNumber)?;
+            Some((index, step.title.clone()))
+        }
+        None => None,
+    };
+    let user = identity.user.as_deref();
+    let ip_hash = identity.ip_hash();
+    let subject = Subject { lesson: Some(oid), user, ip_hash: ip_hash.as_deref() };
+    MODERATION.screen_request(subject, "question", question.step, text, collections).await
+        .map_err(|_| StatusCodes::Rejected)?;
+
+    let history = match user {
+        Some(user) =>
+            collections.conversations
+                .find_one(doc! { "lesson": oid, "user": user }).await
+                .map_err(|_| StatusCodes::GenericError)?
+                .map(|c| c.messages)
+                .unwrap_or_default(),
+        None => Vec::new(),
+    };
+    let mut messages = vec![json!({ "role": "system", "content": system_prompt(&lesson).await })];
+    messages.extend(
+        history[history.len().saturating_sub(HISTORY_MESSAGES)..]
+            .iter()
+            .map(|m| json!({ "role": m.role, "content": m.content }))
+    );
+    let prompt = match &step {
+        Some((index, title)) => format!("Sobre el paso {} ('{}'): {}", index + 1, title, text),
+        None => text.to_string(),
+    };
+    messages.push(json!({ "role": "user", "content": prompt }));
+
+    // Answers share the lesson's token budget, so questions stop once it is spent
+    let llm = Llm::from_env()
+        .map_err(|e| {
+            info!("Failed to initialize LLM client: {}", e);
+            StatusCodes::GenericError
+        })?
+        .with_token_budget(QUOTAS.max_tokens_per_lesson)
+        .with_usage(lesson.usage.clone());
+    if llm.over_budget() {
+        return Err(StatusCodes::QuotaExceeded);
+    }
+    // Stop passing the answer on once the term lists catch it; the whole answer is
+    // screened below
+    let mut streamed = String::new();
+    let mut withheld = false;
+    let result = llm.stream(Stage::Chat, messages, |token| {
+        streamed.push_str(token);
+        withheld = withheld || MODERATION.blocks(&streamed);
+        if !withheld {
+            on_token(token);
+        }
+    }).await;
+    record_usage(lesson_id, user, &llm, collections).await;
+    let completion = result.map_err(|e| {
+        info!("Question about lesson {} failed: {}", lesson_id, e);
+        StatusCodes::GenericError
+    })?;
+    MODERATION.screen_request(subject, "answer", question.step, &completion.value, collections).await
+        .map_err(|_| StatusCodes::Rejected)?;
+
+    let asked = ChatMessage {
+        role: ChatRole::User,
+        content: text.to_string(),
+        step: question.step,
+        model: None,
+        created_at: DateTime::now(),
+    };
+    let answer = ChatMessage {
+        role: ChatRole::Assistant,
+        content: completion.value,
+        step: question.step,
+        model: Some(completion.model),
+        created_at: DateTime::now(),
+    };
+    if let Some(user) = user {
+        let stored = collections.conversations
+            .update_one(
+                doc! { "lesson": oid, "user": user },
+                doc! {
+                    "$push": {
+                        "messages": {
+                            "$each": [bson::to_bson(&asked).unwrap(), bson::to_bson(&answer).unwrap()],
+                            "$slice": -STORED_MESSAGES,
+                        }
+                    },
+                    "$set": { "updated_at": DateTime::now() },
+                }
+            )
+            .upsert(true).await;
+        if let Err(e) = stored {
+            info!("Failed to store conversation for lesson {}: {}", lesson_id, e);
+        }
+    }
+    Ok(answer)
+}
+
+/// Instructions plus everything the lesson says, and its Wikipedia article when it has
+/// one.
+async fn system_prompt(lesson: &LessonRecord) -> String {
+    let steps: Vec<String> = lesson.outline
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let explanation = lesson.steps
+                .get(i)
+                .and_then(|s| s.as_ref())
+                .map(|s| s.explanation.as_str())
+                .unwrap_or(item.prompt.as_str());
+            format!("{}. {}: {}", i + 1, item.title, explanation)
+        })
+        .collect();
+    let mut prompt = format!(
+        "Eres un tutor que responde preguntas de estudiantes sobre una lección. Responde en español, de forma clara y breve, a un nivel {}. \
+        Básate en el contenido de la lección y del artículo de Wikipedia; si la pregunta no tiene relación con la lección, dilo amablemente. \
+        Para mostrar matemáticas, usa KaTeX entre $.\n\nLección: {}\n{}\n\nPasos:\n{}",
+        lesson.audience_prompt(),
+        lesson.title,
+        lesson.description,
+        steps.join("\n")
+    );
+    if
+        let Some(url) = &lesson.wikipedia_url &&
+        let Some(content) = fetch_wikipedia_content(url).await
+    {
+        prompt.push_str("\n\nArtículo de Wikipedia:\n");
+        prompt.push_str(truncate_content(&content, WIKIPEDIA_CONTEXT_CHARS));
+    }
+    prompt
+}
+
+/// The stored conversation of `user` about lesson `lesson_id`.
+pub async fn get_conversation(
+    lesson_id: &str,
+    user: &str,
+    collections: &Collections
+) -> Result<Vec<ChatMessage>, StatusCodes> {
+    let oid = ObjectId::parse_str(lesson_id).map_err(|_| StatusCodes::InvalidID)?;
+    let conversation = collections.conversations
+        .find_one(doc! { "lesson": oid, "user": user }).await
+        .map_err(|_| StatusCodes::GenericError)?;
+    Ok(conversation.map(|c| c.messages).unwrap_or_default())
+}
@@ -0,0 +1,10 @@
+use std::{ env, str::FromStr };
+
+/// Reads the number in environment variable `key`, or `default` if it is unset or
+/// does not parse.
+pub fn env_number<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<T>().ok())
+        .unwrap_or(default)
+}
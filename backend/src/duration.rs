@@ -0,0 +1,53 @@
+use crate::types::{ MediaType, Step };
+
+/// Silent reading speed of a student reading Spanish.
+const READING_WORDS_PER_MINUTE: f64 = 180.0;
+/// Speed of the narration voice.
+const SPEAKING_WORDS_PER_MINUTE: f64 = 150.0;
+/// Time to look at an image on top of reading its explanation.
+const IMAGE_VIEW_SECONDS: u32 = 10;
+
+fn words_to_seconds(text: &str, words_per_minute: f64) -> u32 {
+    let words = text.split_whitespace().count() as f64;
+    (words / words_per_minute * 60.0).ceil() as u32
+}
+
+/// Seconds to read a step's explanation, and look at its image for image steps.
+pub fn reading_seconds(media_type: MediaType, explanation: &str) -> u32 {
+    let reading = words_to_seconds(explanation, READING_WORDS_PER_MINUTE);
+    match media_type {
+        MediaType::Image => reading + IMAGE_VIEW_SECONDS,
+        MediaType::Text => reading,
+    }
+}
+
+/// Seconds the narration of `speech` takes, estimated from the text. Steps get no
+/// narration audio while TTS is turned off, so there is no audio length to use.
+pub fn listening_seconds(speech: &str) -> u32 {
+    words_to_seconds(speech, SPEAKING_WORDS_PER_MINUTE)
+}
+
+/// Estimated time on a step: the narration plays while the student reads, so
+/// whichever takes longer.
+pub fn step_seconds(step: &Step) -> u32 {
+    step.reading_time.max(step.listening_time)
+}
+
+/// Estimated time for every generated step of a lesson.
+pub fn lesson_seconds(steps: &[Option<Step>]) -> u32 {
+    steps.iter().flatten().map(step_seconds).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_from_word_counts() {
+        let words = vec!["palabra"; 300].join(" ");
+        assert_eq!(reading_seconds(MediaType::Text, &words), 100);
+        assert_eq!(reading_seconds(MediaType::Image, &words), 110);
+        assert_eq!(listening_seconds(&words), 120);
+        assert_eq!(listening_seconds(""), 0);
+    }
+}
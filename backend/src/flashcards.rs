@@ -0,0 +1,466 @@
+use std::{ collections::HashMap, env, fs, io::{ Cursor, Write } };
+use base64::{ engine::general_purpose, Engine };
+use mongodb::bson::{ self, doc, oid::ObjectId, DateTime };
+use rusqlite::{ params, Connection };
+use serde_json::json;
+use sha1::{ Digest, Sha1 };
+use tracing::info;
+use zip::{ write::SimpleFileOptions, ZipWriter };
+use crate::{
+    llm::{ Llm, Stage },
+    structured::Flashcards,
+    types::{ Deck, Image, LessonRecord, LessonStatus, MediaType, StatusCodes },
+    utils::{ record_user_usage, Collections },
+};
+
+/// Note type shared by every exported deck, so importing several lessons into Anki
+/// does not create a note type per lesson.
+const ANKI_MODEL_ID: i64 = 1_716_203_114_000;
+const FIELD_SEPARATOR: &str = "\u{1f}";
+
+/// Returns the stored deck for lesson `lesson_id`, generating it on first request.
+/// `user` is charged for the generation.
+pub async fn get_deck(
+    lesson_id: &str,
+    user: Option<&str>,
+    collections: &Collections
+) -> Result<(LessonRecord, Deck), StatusCodes> {
+    let oid = ObjectId::parse_str(lesson_id).map_err(|_| StatusCodes::InvalidID)?;
+    let lesson = collections.lessons
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::LessonNotFound)?;
+    let stored = collections.decks
+        .find_one(doc! { "lesson": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?;
+    if let Some(deck) = stored {
+        return Ok((lesson, deck));
+    }
+    if lesson.status == LessonStatus::Generating || lesson.status == LessonStatus::Review {
+        return Err(StatusCodes::InvalidState);
+    }
+    let deck = generate_deck(oid, &lesson, user, collections).await?;
+    Ok((lesson, deck))
+}
+
+async fn generate_deck(
+    oid: ObjectId,
+    lesson: &LessonRecord,
+    user: Option<&str>,
+    collections: &Collections
+) -> Result<Deck, StatusCodes> {
+    let steps: Vec<String> = lesson.steps
+        .iter()
+        .enumerate()
+        .filter_map(|(i, step)| step.as_ref().map(|step| format!("[{}] {}: {}", i, step.title, step.explanation)))
+        .collect();
+    if steps.is_empty() {
+        return Err(StatusCodes::InvalidState);
+    }
+    let prompt = format!(
+        "A partir de la siguiente lección '{}', crea tarjetas de estudio (flashcards) con los términos y conceptos clave para repasar. \
+        Devuelve un objeto JSON con un arreglo 'cards'; cada tarjeta tiene 'front' (un término o una pregunta corta), \
+        'back' (la definición o respuesta, breve) y 'step' (el número entre corchetes del paso del que sale). \
+        Máximo 30 tarjetas, sin repetir conceptos. Texto en español. Para mostrar matemáticas, usa KaTeX entre $. Solo JSON sin otros textos.\n\n{}",
+        lesson.title,
+        steps.join("\n")
+    );
+
+    let llm = Llm::from_env().map_err(|e| {
+        info!("Failed to initialize LLM client: {}", e);
+        StatusCodes::GenericError
+    })?;
+    let result = llm.complete::<Flashcards>(Stage::Flashcards, vec![json!({ "role": "user", "content": prompt })]).await;
+    let usage = llm.take_pending_usage();
+    if usage.requests > 0 && let Err(e) = record_user_usage(user, &usage, collections).await {
+        info!("Failed to record flashcard usage for lesson {}: {}", oid, e);
+    }
+    let completion = result.map_err(|e| {
+        info!("Flashcard generation failed for lesson {}: {}", oid, e);
+        StatusCodes::GenericError
+    })?;
+
+    // Drop cards that point at steps that do not exist and attach step images
+    let cards = completion.value.cards
+        .into_iter()
+        .filter_map(|mut card| {
+            let step = lesson.steps.get(card.step as usize)?.as_ref()?;
+            if step.media_type == MediaType::Image {
+                card.image = step.image.clone();
+            }
+            Some(card)
+        })
+        .collect::<Vec<_>>();
+    if cards.is_empty() {
+        return Err(StatusCodes::GenericError);
+    }
+    let deck = Deck { id: None, lesson: oid, cards, model: completion.model, created_at: DateTime::now() };
+    // Another request may have generated the deck in the meantime; keep whichever was first
+    collections.decks
+        .update_one(doc! { "lesson": oid }, doc! { "$setOnInsert": bson::to_document(&deck).unwrap() })
+        .upsert(true).await
+        .map_err(|_| StatusCodes::GenericError)?;
+    collections.decks
+        .find_one(doc! { "lesson": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::GenericError)
+}
+
+/// `front,back,step` rows with a header, quoted as in RFC 4180.
+pub fn to_csv(deck: &Deck) -> String {
+    fn quote(field: &str) -> String {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    }
+    let mut csv = String::from("front,back,step\r\n");
+    for card in &deck.cards {
+        csv.push_str(&format!("{},{},{}\r\n", quote(&card.front), quote(&card.back), card.step + 1));
+    }
+    csv
+}
+
+/// Loads the images used by `deck`, keyed by image id.
+pub async fn load_images(deck: &Deck, collections: &Collections) -> HashMap<String, Image> {
+    let mut images = HashMap::new();
+    for id in deck.cards.iter().filter_map(|card| card.image.as_ref()) {
+        if images.contains_key(id) {
+            continue;
+        }
+        let Ok(oid) = ObjectId::parse_str(id) else {
+            continue;
+        };
+        if let Ok(Some(image)) = collections.images.find_one(doc! { "_id": oid }).await {
+            images.insert(id.clone(), image);
+        }
+    }
+    images
+}
+
+/// Turns lesson text into Anki field HTML: escapes markup, switches KaTeX `$...$`
+/// delimiters to the `\(...\)` and `\[...\]` that Anki's MathJax expects, and keeps
+/// line breaks.
+fn to_anki_html(text: &str) -> String {
+    let escaped = text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let mut html = String::with_capacity(escaped.len());
+    let mut rest = escaped.as_str();
+    while let Some(start) = rest.find('$') {
+        html.push_str(&rest[..start]);
+        let display = rest[start..].starts_with("$$");
+        let delimiter = if display { "$$" } else { "$" };
+        let body = &rest[start + delimiter.len()..];
+        let Some(end) = body.find(delimiter) else {
+            html.push_str(&rest[start..]);
+            rest = "";
+            break;
+        };
+        let (open, close) = if display { ("\\[", "\\]") } else { ("\\(", "\\)") };
+        html.push_str(open);
+        html.push_str(&body[..end]);
+        html.push_str(close);
+        rest = &body[end + delimiter.len()..];
+    }
+    html.push_str(rest);
+    html.replace('\n', "<br>")
+}
+
+/// Splits a stored `data:image/<ext>;base64,...` URL into its extension and bytes.
+fn decode_image(image: &Image) -> Option<(String, Vec<u8>)> {
+    let (header, data) = image.data.strip_prefix("data:image/")?.split_once(";base64,")?;
+    let extension = header.split(['+', ';']).next().unwrap_or("png").to_string();
+    Some((extension, general_purpose::STANDARD.decode(data).ok()?))
+}
+
+/// Id derived from `seed`, kept below 2^53 so Anki's JSON handling does not lose
+/// precision.
+fn stable_id(seed: &str) -> i64 {
+    let digest = Sha1::digest(seed.as_bytes());
+    (u64::from_be_bytes(digest[..8].try_into().unwrap()) >> 11) as i64
+}
+
+/// Builds an Anki package: a zip holding the `collection.anki2` SQLite database, a
+/// `media` index and one numbered file per image. Blocking; run it off the runtime.
+pub fn to_apkg(deck: &Deck, title: &str, images: &HashMap<String, Image>) -> Result<Vec<u8>, String> {
+    let lesson_id = deck.lesson.to_string();
+    let now = chrono::Utc::now();
+    let now_secs = now.timestamp();
+    let now_millis = now.timestamp_millis();
+    let deck_id = stable_id(&format!("deck:{}", lesson_id));
+    let deck_name = if title.trim().is_empty() { "Canvas".to_string() } else { format!("Canvas::{}", title.trim()) };
+
+    // Media files are named "0", "1", ... inside the zip and mapped to their real names
+    let mut media = Vec::new();
+    let mut media_names = HashMap::new();
+    for (id, image) in images {
+        if let Some((extension, bytes)) = decode_image(image) {
+            let name = format!("canvas-{}.{}", id, extension);
+            media_names.insert(id.clone(), name.clone());
+            media.push((name, bytes));
+        }
+    }
+
+    let path = env::temp_dir().join(format!("canvas-{}.anki2", ObjectId::new()));
+    let result = write_collection(&path, deck, &deck_name, deck_id, &media_names, now_secs, now_millis);
+    let collection = result.and_then(|_| fs::read(&path).map_err(|e| e.to_string()));
+    fs::remove_file(&path).ok();
+    let collection = collection.map_err(|e| format!("Failed to build Anki collection: {}", e))?;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let zip_error = |e: zip::result::ZipError| format!("Failed to build Anki package: {}", e);
+    zip.start_file("collection.anki2", options).map_err(zip_error)?;
+    zip.write_all(&collection).map_err(|e| e.to_string())?;
+    let index: HashMap<String, &String> = media
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (i.to_string(), name))
+        .collect();
+    zip.start_file("media", options).map_err(zip_error)?;
+    zip.write_all(json!(index).to_string().as_bytes()).map_err(|e| e.to_string())?;
+    for (i, (_, bytes)) in media.iter().enumerate() {
+        zip.start_file(i.to_string(), options).map_err(zip_error)?;
+        zip.write_all(bytes).map_err(|e| e.to_string())?;
+    }
+    Ok(zip.finish().map_err(zip_error)?.into_inner())
+}
+
+fn write_collection(
+    path: &std::path::Path,
+    deck: &Deck,
+    deck_name: &str,
+    deck_id: i64,
+    media_names: &HashMap<String, String>,
+    now_secs: i64,
+    now_millis: i64
+) -> Result<(), String> {
+    let db = Connection::open(path).map_err(|e| e.to_string())?;
+    db.execute_batch(ANKI_SCHEMA).map_err(|e| e.to_string())?;
+
+    let model =
+        json!({
+        ANKI_MODEL_ID.to_string(): {
+            "id": ANKI_MODEL_ID,
+            "name": "Canvas",
+            "type": 0,
+            "mod": now_secs,
+            "usn": -1,
+            "sortf": 0,
+            "did": deck_id,
+            "tmpls": [{
+                "name": "Card 1",
+                "ord": 0,
+                "qfmt": "{{Front}}",
+                "afmt": "{{FrontSide}}<hr id=answer>{{Back}}",
+                "did": null,
+                "bqfmt": "",
+                "bafmt": ""
+            }],
+            "flds": [
+                { "name": "Front", "ord": 0, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [] },
+                { "name": "Back", "ord": 1, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [] }
+            ],
+            "css": ".card { font-family: arial; font-size: 20px; text-align: center; color: black; background-color: white; } img { max-width: 100%; }",
+            "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+            "latexPost": "\\end{document}",
+            "tags": [],
+            "vers": [],
+            "req": [[0, "all", [0]]]
+        }
+    });
+    let deck_conf = |id: i64, name: &str| json!({
+        "id": id,
+        "name": name,
+        "desc": "",
+        "mod": now_secs,
+        "usn": -1,
+        "collapsed": false,
+        "newToday": [0, 0],
+        "revToday": [0, 0],
+        "lrnToday": [0, 0],
+        "timeToday": [0, 0],
+        "dyn": 0,
+        "conf": 1,
+        "extendNew": 10,
+        "extendRev": 50
+    });
+    let decks =
+        json!({
+        "1": deck_conf(1, "Default"),
+        deck_id.to_string(): deck_conf(deck_id, deck_name),
+    });
+    let dconf =
+        json!({
+        "1": {
+            "id": 1,
+            "name": "Default",
+            "mod": 0,
+            "usn": 0,
+            "maxTaken": 60,
+            "autoplay": true,
+            "timer": 0,
+            "replayq": true,
+            "new": { "bury": true, "delays": [1, 10], "initialFactor": 2500, "ints": [1, 4, 7], "order": 1, "perDay": 20, "separate": true },
+            "rev": { "bury": true, "ease4": 1.3, "fuzz": 0.05, "ivlFct": 1, "maxIvl": 36500, "minSpace": 1, "perDay": 100 },
+            "lapse": { "delays": [10], "leechAction": 0, "leechFails": 8, "minInt": 1, "mult": 0 }
+        }
+    });
+    let conf =
+        json!({
+        "activeDecks": [1],
+        "curDeck": 1,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": null,
+        "nextPos": deck.cards.len() + 1,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true
+    });
+    db.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?3, 11, 0, 0, 0, ?4, ?5, ?6, ?7, '{}')",
+        params![
+            now_secs,
+            now_millis,
+            now_millis,
+            conf.to_string(),
+            model.to_string(),
+            decks.to_string(),
+            dconf.to_string()
+        ]
+    ).map_err(|e| e.to_string())?;
+
+    for (i, card) in deck.cards.iter().enumerate() {
+        let front = to_anki_html(&card.front);
+        let mut back = to_anki_html(&card.back);
+        if let Some(name) = card.image.as_ref().and_then(|id| media_names.get(id)) {
+            back.push_str(&format!("<br><img src=\"{}\">", name));
+        }
+        let checksum = Sha1::digest(card.front.trim().as_bytes());
+        let checksum = u32::from_be_bytes(checksum[..4].try_into().unwrap()) as i64;
+        let guid = format!("{:x}", stable_id(&format!("note:{}:{}", deck.lesson, card.front)));
+        let note_id = now_millis + (i as i64);
+        db.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, '', ?5, ?6, ?7, 0, '')",
+            params![
+                note_id,
+                guid,
+                ANKI_MODEL_ID,
+                now_secs,
+                format!("{}{}{}", front, FIELD_SEPARATOR, back),
+                card.front.trim(),
+                checksum
+            ]
+        ).map_err(|e| e.to_string())?;
+        db.execute(
+            "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![note_id, note_id, deck_id, now_secs, (i as i64) + 1]
+        ).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Schema of an Anki 2.1 legacy (`collection.anki2`, version 11) collection.
+const ANKI_SCHEMA: &str =
+    "
+CREATE TABLE col (
+    id integer PRIMARY KEY, crt integer NOT NULL, mod integer NOT NULL, scm integer NOT NULL,
+    ver integer NOT NULL, dty integer NOT NULL, usn integer NOT NULL, ls integer NOT NULL,
+    conf text NOT NULL, models text NOT NULL, decks text NOT NULL, dconf text NOT NULL, tags text NOT NULL
+);
+CREATE TABLE notes (
+    id integer PRIMARY KEY, guid text NOT NULL, mid integer NOT NULL, mod integer NOT NULL,
+    usn integer NOT NULL, tags text NOT NULL, flds text NOT NULL, sfld integer NOT NULL,
+    csum integer NOT NULL, flags integer NOT NULL, data text NOT NULL
+);
+CREATE TABLE cards (
+    id integer PRIMARY KEY, nid integer NOT NULL, did integer NOT NULL, ord integer NOT NULL,
+    mod integer NOT NULL, usn integer NOT NULL, type integer NOT NULL, queue integer NOT NULL,
+    due integer NOT NULL, ivl integer NOT NULL, factor integer NOT NULL, reps integer NOT NULL,
+    lapses integer NOT NULL, left integer NOT NULL, odue integer NOT NULL, odid integer NOT NULL,
+    flags integer NOT NULL, data text NOT NULL
+);
+CREATE TABLE revlog (
+    id integer PRIMARY KEY, cid integer NOT NULL, usn integer NOT NULL, ease integer NOT NULL,
+    ivl integer NOT NULL, lastIvl integer NOT NULL, factor integer NOT NULL, time integer NOT NULL,
+    type integer NOT NULL
+);
+CREATE TABLE graves (usn integer NOT NULL, oid integer NOT NULL, type integer NOT NULL);
+CREATE INDEX ix_notes_usn ON notes (usn);
+CREATE INDEX ix_cards_usn ON cards (usn);
+CREATE INDEX ix_revlog_usn ON revlog (usn);
+CREATE INDEX ix_cards_nid ON cards (nid);
+CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+CREATE INDEX ix_revlog_cid ON revlog (cid);
+CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use zip::ZipArchive;
+    use crate::types::Flashcard;
+    use super::*;
+
+    fn card(front: &str, back: &str, step: u32, image: Option<&str>) -> Flashcard {
+        Flashcard { front: front.into(), back: back.into(), step, image: image.map(String::from) }
+    }
+
+    fn deck(cards: Vec<Flashcard>) -> Deck {
+        Deck { id: None, lesson: ObjectId::new(), cards, model: "test".into(), created_at: DateTime::now() }
+    }
+
+    #[test]
+    fn csv_quotes_every_field() {
+        let csv = to_csv(&deck(vec![card("Say \"hola\"", "a, b\nc", 0, None), card("x", "y", 2, None)]));
+        assert_eq!(csv, "front,back,step\r\n\"Say \"\"hola\"\"\",\"a, b\nc\",1\r\n\"x\",\"y\",3\r\n");
+    }
+
+    #[test]
+    fn anki_html_escapes_markup_and_converts_math() {
+        assert_eq!(to_anki_html("a < b & $x^2$"), "a &lt; b &amp; \\(x^2\\)");
+        assert_eq!(to_anki_html("$$\\frac{1}{2}$$\nend"), "\\[\\frac{1}{2}\\]<br>end");
+        assert_eq!(to_anki_html("costs $5"), "costs $5");
+    }
+
+    #[test]
+    fn apkg_opens_as_an_anki_collection() {
+        let image_id = ObjectId::new().to_string();
+        let deck = deck(vec![card("Célula", "Unidad $1$", 0, None), card("Núcleo", "Centro", 1, Some(&image_id))]);
+        let bytes = [1u8, 2, 3];
+        let images = HashMap::from([
+            (image_id.clone(), Image { data: format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(bytes)) }),
+        ]);
+        let package = to_apkg(&deck, "Biología", &images).unwrap();
+
+        let mut zip = ZipArchive::new(Cursor::new(package)).unwrap();
+        let mut media = String::new();
+        zip.by_name("media").unwrap().read_to_string(&mut media).unwrap();
+        let name = format!("canvas-{}.png", image_id);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&media).unwrap(), json!({ "0": name }));
+        let mut file = Vec::new();
+        zip.by_name("0").unwrap().read_to_end(&mut file).unwrap();
+        assert_eq!(file, bytes);
+
+        let mut collection = Vec::new();
+        zip.by_name("collection.anki2").unwrap().read_to_end(&mut collection).unwrap();
+        let path = env::temp_dir().join(format!("canvas-test-{}.anki2", ObjectId::new()));
+        fs::write(&path, collection).unwrap();
+        let db = Connection::open(&path).unwrap();
+        let decks: String = db.query_row("SELECT decks FROM col", [], |row| row.get(0)).unwrap();
+        let fields: Vec<String> = db
+            .prepare("SELECT flds FROM notes ORDER BY id").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let cards: i64 = db.query_row("SELECT count(*) FROM cards", [], |row| row.get(0)).unwrap();
+        drop(db);
+        fs::remove_file(&path).ok();
+
+        assert!(decks.contains("Canvas::Biología"));
+        assert_eq!(fields, [
+            format!("Célula{}Unidad \\(1\\)", FIELD_SEPARATOR),
+            format!("Núcleo{}Centro<br><img src=\"{}\">", FIELD_SEPARATOR, name),
+        ]);
+        assert_eq!(cards, 2);
+    }
+}
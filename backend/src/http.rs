@@ -0,0 +1,251 @@
+use std::{ collections::HashMap, env, sync::{ LazyLock, Mutex }, time::{ Duration, Instant } };
+use rand::Rng;
+use reqwest::{ header, RequestBuilder, Response, StatusCode };
+use tracing::info;
+use crate::config::env_number;
+
+/// Wikimedia asks API clients to identify themselves with a descriptive User-Agent
+/// that includes a way to reach the operator.
+const DEFAULT_USER_AGENT: &str =
+    "CanvasBot/0.1 (https://canvas.notaroomba.dev; https://github.com/NotARoomba/canvas)";
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub default_timeout: Duration,
+    /// Timeouts for specific upstreams, matched against the end of the host name.
+    pub host_timeouts: Vec<(String, Duration)>,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+}
+
+fn env_secs(key: &str, default: u64) -> Duration {
+    Duration::from_secs(env_number(key, default))
+}
+
+impl HttpConfig {
+    pub fn from_env() -> Self {
+        HttpConfig {
+            max_retries: env_number("HTTP_MAX_RETRIES", 3),
+            base_delay: Duration::from_millis(500),
+            max_delay: env_secs("HTTP_MAX_BACKOFF_SECS", 30),
+            default_timeout: env_secs("HTTP_TIMEOUT_SECS", 30),
+            host_timeouts: vec![
+                ("openrouter.ai".to_string(), env_secs("LLM_TIMEOUT_SECS", 60)),
+                ("wikipedia.org".to_string(), env_secs("WIKIPEDIA_TIMEOUT_SECS", 15)),
+                ("wikimedia.org".to_string(), env_secs("WIKIPEDIA_TIMEOUT_SECS", 15))
+            ],
+            breaker_threshold: env_number("HTTP_BREAKER_THRESHOLD", 5),
+            breaker_cooldown: env_secs("HTTP_BREAKER_COOLDOWN_SECS", 30),
+        }
+    }
+
+    fn timeout_for(&self, host: &str) -> Duration {
+        self.host_timeouts
+            .iter()
+            .find(|(suffix, _)| host == suffix || host.ends_with(&format!(".{}", suffix)))
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or(self.default_timeout)
+    }
+}
+
+/// Per-upstream circuit breaker: after `breaker_threshold` consecutive failures the
+/// host is skipped for `breaker_cooldown`, then a single trial request decides
+/// whether it closes again.
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+/// Permission to send a request to `host`, from `HttpClient::acquire`. For the trial
+/// request of a half-open circuit, dropping the permit without recording an outcome
+/// (for example when the request's future is cancelled) lets another trial through.
+struct Permit<'a> {
+    client: &'a HttpClient,
+    host: String,
+    trial: bool,
+}
+
+impl Permit<'_> {
+    fn record(mut self, success: bool) {
+        self.trial = false;
+        self.client.record(&self.host, success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            let mut breakers = self.client.breakers.lock().unwrap();
+            if let Some(breaker) = breakers.get_mut(&self.host) {
+                breaker.trial_in_flight = false;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    config: HttpConfig,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+pub static HTTP: LazyLock<HttpClient> = LazyLock::new(HttpClient::from_env);
+
+impl HttpClient {
+    pub fn from_env() -> Self {
+        let user_agent = env::var("HTTP_USER_AGENT").unwrap_or_else(|_| DEFAULT_USER_AGENT.to_string());
+        let client = reqwest::Client
+            ::builder()
+            .user_agent(user_agent)
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to build HTTP client");
+        HttpClient { client, config: HttpConfig::from_env(), breakers: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Sends `request`, retrying connection errors, timeouts, 429 and 5xx responses
+    /// with exponential backoff and jitter (or the upstream's `Retry-After`). Any other
+    /// response is returned as-is for the caller to inspect.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, String> {
+        let probe = request
+            .try_clone()
+            .ok_or_else(|| "Request body cannot be retried".to_string())?
+            .build()
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let host = probe.url().host_str().unwrap_or_default().to_string();
+        let timeout = probe.timeout().copied().unwrap_or_else(|| self.config.timeout_for(&host));
+
+        let permit = self.acquire(&host)?;
+        let mut attempt = 0;
+        loop {
+            let result = request
+                .try_clone()
+                .expect("Request was cloneable above")
+                .timeout(timeout)
+                .send().await;
+            let (error, retry_after) = match result {
+                Ok(response) if !is_retryable(response.status()) => {
+                    permit.record(true);
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    let retry_after = retry_after(&response);
+                    (format!("{} responded with status {}", host, response.status()), retry_after)
+                }
+                Err(e) if e.is_timeout() => (format!("{} timed out after {:?}", host, timeout), None),
+                Err(e) => (format!("Request to {} failed: {}", host, e), None),
+            };
+            if attempt >= self.config.max_retries {
+                permit.record(false);
+                return Err(error);
+            }
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt)).min(self.config.max_delay);
+            info!("{}; retrying in {:?} (attempt {})", error, delay, attempt + 1);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Full jitter: a random delay between zero and `base * 2^attempt`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self.config.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.config.max_delay);
+        let millis = rand::rng().random_range(0..=ceiling.as_millis() as u64);
+        Duration::from_millis(millis)
+    }
+
+    fn acquire(&self, host: &str) -> Result<Permit<'_>, String> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(host.to_string()).or_default();
+        let trial = match breaker.open_until {
+            Some(until) if Instant::now() < until => {
+                return Err(format!("Circuit for {} is open after repeated failures", host));
+            }
+            Some(_) if breaker.trial_in_flight => {
+                return Err(format!("Circuit for {} is waiting on a trial request", host));
+            }
+            Some(_) => {
+                breaker.trial_in_flight = true;
+                true
+            }
+            None => false,
+        };
+        Ok(Permit { client: self, host: host.to_string(), trial })
+    }
+
+    fn record(&self, host: &str, success: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(host.to_string()).or_default();
+        breaker.trial_in_flight = false;
+        if success {
+            breaker.failures = 0;
+            breaker.open_until = None;
+            return;
+        }
+        breaker.failures += 1;
+        if breaker.open_until.is_some() || breaker.failures >= self.config.breaker_threshold {
+            info!("Opening circuit for {} after {} failures", host, breaker.failures);
+            breaker.open_until = Some(Instant::now() + self.config.breaker_cooldown);
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Parses `Retry-After` as either delay seconds or an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn half_open(client: &HttpClient, host: &str) {
+        let mut breakers = client.breakers.lock().unwrap();
+        let breaker = breakers.entry(host.to_string()).or_default();
+        breaker.failures = client.config.breaker_threshold;
+        breaker.open_until = Some(Instant::now() - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn dropped_trial_lets_another_trial_through() {
+        let client = HttpClient::from_env();
+        half_open(&client, "example.org");
+        let trial = client.acquire("example.org").unwrap();
+        assert!(client.acquire("example.org").is_err());
+        drop(trial);
+        assert!(client.acquire("example.org").is_ok());
+    }
+
+    #[test]
+    fn successful_trial_closes_the_circuit() {
+        let client = HttpClient::from_env();
+        half_open(&client, "example.org");
+        client.acquire("example.org").unwrap().record(true);
+        let breakers = client.breakers.lock().unwrap();
+        assert_eq!(breakers["example.org"].open_until, None);
+    }
+}
@@ -0,0 +1,30 @@
+use std::{ collections::HashMap, sync::{ LazyLock, Mutex } };
+use tokio_util::sync::CancellationToken;
+
+/// Cancellation tokens for lesson pipelines that are currently running, keyed by
+/// lesson id.
+static JOBS: LazyLock<Mutex<HashMap<String, CancellationToken>>> = LazyLock::new(||
+    Mutex::new(HashMap::new())
+);
+
+pub fn register(lesson_id: &str) -> CancellationToken {
+    let token = CancellationToken::new();
+    JOBS.lock().unwrap().insert(lesson_id.to_string(), token.clone());
+    token
+}
+
+pub fn finish(lesson_id: &str) {
+    JOBS.lock().unwrap().remove(lesson_id);
+}
+
+/// Signals the pipeline for `lesson_id` to stop. Returns false if no pipeline is
+/// running for that lesson.
+pub fn cancel(lesson_id: &str) -> bool {
+    match JOBS.lock().unwrap().get(lesson_id) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::{ BTreeMap, HashMap },
+    env,
+    sync::{ Arc, LazyLock, Mutex },
+    time::Duration,
+};
+use reqwest::header;
+use serde_json::{ json, Value };
+use strum_macros::AsRefStr;
+use tracing::info;
+use crate::{ config::env_number, http::HTTP, structured::{ self, StructuredOutput }, types::TokenUsage };
+
+const OPENROUTER_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+
+/// Every LLM call in the lesson pipeline belongs to one of these stages, each with
+/// its own ordered list of models to try.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsRefStr)]
+pub enum Stage {
+    #[strum(serialize = "outline")]
+    Outline,
+    #[strum(serialize = "step_text")]
+    StepText,
+    #[strum(serialize = "image_explanation")]
+    ImageExplanation,
+    #[strum(serialize = "references")]
+    References,
+    #[strum(serialize = "wiki_lookup")]
+    WikiLookup,
+    #[strum(serialize = "chat")]
+    Chat,
+    #[strum(serialize = "flashcards")]
+    Flashcards,
+    #[strum(serialize = "glossary")]
+    Glossary,
+    #[strum(serialize = "rewrite")]
+    Rewrite,
+    #[strum(serialize = "moderation")]
+    Moderation,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 10] = [
+        Stage::Outline,
+        Stage::StepText,
+        Stage::ImageExplanation,
+        Stage::References,
+        Stage::WikiLookup,
+        Stage::Chat,
+        Stage::Flashcards,
+        Stage::Glossary,
+        Stage::Rewrite,
+        Stage::Moderation,
+    ];
+
+    fn env_key(&self) -> &'static str {
+        match self {
+            Stage::Outline => "OUTLINE_MODELS",
+            Stage::StepText => "STEP_TEXT_MODELS",
+            Stage::ImageExplanation => "IMAGE_EXPLANATION_MODELS",
+            Stage::References => "REFERENCES_MODELS",
+            Stage::WikiLookup => "WIKI_LOOKUP_MODELS",
+            Stage::Chat => "CHAT_MODELS",
+            Stage::Flashcards => "FLASHCARD_MODELS",
+            Stage::Glossary => "GLOSSARY_MODELS",
+            Stage::Rewrite => "REWRITE_MODELS",
+            Stage::Moderation => "MODERATION_MODELS",
+        }
+    }
+
+    fn default_models(&self) -> &'static [&'static str] {
+        match self {
+            Stage::StepText | Stage::ImageExplanation | Stage::Rewrite =>
+                &["google/gemini-2.5-flash-preview", "google/gemini-2.0-flash-001"],
+            Stage::Outline |
+            Stage::References |
+            Stage::WikiLookup |
+            Stage::Flashcards |
+            Stage::Glossary |
+            Stage::Moderation =>
+                &["google/gemini-2.0-flash-lite-001", "google/gemini-2.0-flash-001"],
+            Stage::Chat => &["google/gemini-2.0-flash-001", "google/gemini-2.0-flash-lite-001"],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub chains: HashMap<Stage, Vec<String>>,
+    pub timeout: Duration,
+    pub repair_attempts: u32,
+}
+
+impl ModelConfig {
+    /// Reads comma separated model lists such as `OUTLINE_MODELS=a,b,c`, falling back
+    /// to the built-in chain for any stage that is not configured.
+    pub fn from_env() -> Self {
+        let chains = Stage::ALL.iter()
+            .map(|stage| {
+                let models = env
+                    ::var(stage.env_key())
+                    .ok()
+                    .map(|v|
+                        v
+                            .split(',')
+                            .map(|m| m.trim().to_string())
+                            .filter(|m| !m.is_empty())
+                            .collect::<Vec<_>>()
+                    )
+                    .filter(|models| !models.is_empty())
+                    .unwrap_or_else(||
+                        stage
+                            .default_models()
+                            .iter()
+                            .map(|m| m.to_string())
+                            .collect()
+                    );
+                (*stage, models)
+            })
+            .collect();
+        ModelConfig {
+            chains,
+            timeout: Duration::from_secs(env_number("LLM_TIMEOUT_SECS", 60)),
+            repair_attempts: env_number("LLM_REPAIR_ATTEMPTS", 1),
+        }
+    }
+
+    pub fn models(&self, stage: Stage) -> &[String] {
+        self.chains.get(&stage).map(|m| m.as_slice()).unwrap_or_default()
+    }
+}
+
+pub static MODELS: LazyLock<ModelConfig> = LazyLock::new(ModelConfig::from_env);
+
+/// A successful structured completion together with the model that produced it.
+#[derive(Debug, Clone)]
+pub struct Completion<T> {
+    pub value: T,
+    pub model: String,
+}
+
+/// Usage of every request an `Llm` has made, per stage, including failed attempts
+/// and fallbacks. `pending` holds what has not been flushed to storage yet.
+#[derive(Debug, Default)]
+struct UsageLedger {
+    total: BTreeMap<String, TokenUsage>,
+    pending: BTreeMap<String, TokenUsage>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Llm {
+    api_key: String,
+    usage: Arc<Mutex<UsageLedger>>,
+    /// Tokens this client may use across all stages; `None` means unlimited.
+    token_budget: Option<u64>,
+}
+
+impl Llm {
+    pub fn from_env() -> Result<Self, String> {
+        let api_key = env
+            ::var("OPENROUTER_API_KEY")
+            .or_else(|_| env::var("OR_API_KEY"))
+            .map_err(|_| "OPENROUTER_API_KEY must be set".to_string())?;
+        Ok(Llm { api_key, usage: Arc::default(), token_budget: None })
+    }
+
+    /// Makes requests fail once `tokens` have been used. A request that is already
+    /// running may go past the budget; a `0` budget disables the check.
+    pub fn with_token_budget(mut self, tokens: u64) -> Self {
+        self.token_budget = (tokens > 0).then_some(tokens);
+        self
+    }
+
+    /// Starts from usage recorded by an earlier run on the same lesson, so totals and
+    /// the token budget carry over.
+    pub fn with_usage(self, usage: BTreeMap<String, TokenUsage>) -> Self {
+        self.usage.lock().unwrap().total = usage;
+        self
+    }
+
+    pub fn over_budget(&self) -> bool {
+        let Some(budget) = self.token_budget else {
+            return false;
+        };
+        let used: u64 = self.usage.lock().unwrap().total.values().map(TokenUsage::total_tokens).sum();
+        used >= budget
+    }
+
+    /// Returns the usage recorded since the previous call and resets it.
+    pub fn take_pending_usage(&self) -> TokenUsage {
+        let mut usage = TokenUsage::default();
+        for stage in self.take_pending_stage_usage().values() {
+            usage.add(stage);
+        }
+        usage
+    }
+
+    /// Like `take_pending_usage`, but per stage.
+    pub fn take_pending_stage_usage(&self) -> BTreeMap<String, TokenUsage> {
+        std::mem::take(&mut self.usage.lock().unwrap().pending)
+    }
+
+    fn record_usage(&self, stage: Stage, usage: &TokenUsage) {
+        let mut ledger = self.usage.lock().unwrap();
+        ledger.total.entry(stage.as_ref().to_string()).or_default().add(usage);
+        ledger.pending.entry(stage.as_ref().to_string()).or_default().add(usage);
+    }
+
+    /// Runs `messages` against each model configured for `stage` in order and returns
+    /// the first response that arrives in time and parses as `T`. A response that
+    /// fails validation is sent back to the same model with the error before moving
+    /// on to the next one.
+    pub async fn complete<T: StructuredOutput>(
+        &self,
+        stage: Stage,
+        messages: Vec<Value>
+    ) -> Result<Completion<T>, String> {
+        self.complete_inner(stage, messages, None).await
+    }
+
+    /// Like `complete`, but streams each attempt and calls `on_progress` with the raw
+    /// output received so far. The text starts over when a response is retried or a
+    /// fallback model is used.
+    pub async fn complete_streaming<T: StructuredOutput>(
+        &self,
+        stage: Stage,
+        messages: Vec<Value>,
+        mut on_progress: impl FnMut(&str) + Send
+    ) -> Result<Completion<T>, String> {
+        self.complete_inner(stage, messages, Some(&mut on_progress)).await
+    }
+
+    async fn complete_inner<T: StructuredOutput>(
+        &self,
+        stage: Stage,
+        messages: Vec<Value>,
+        mut on_progress: Option<&mut (dyn FnMut(&str) + Send)>
+    ) -> Result<Completion<T>, String> {
+        let mut last_error = format!("No models configured for stage {}", stage.as_ref());
+        for model in MODELS.models(stage) {
+            let mut conversation = messages.clone();
+            for attempt in 0..=MODELS.repair_attempts {
+                if self.over_budget() {
+                    return Err("Token budget exceeded".to_string());
+                }
+                let response_format = response_format(T::NAME, T::schema());
+                let request = match on_progress.as_deref_mut() {
+                    Some(on_progress) => {
+                        let mut raw = String::new();
+                        self.stream_request(stage, model, &conversation, Some(response_format), |token| {
+                            raw.push_str(token);
+                            on_progress(&raw);
+                        }).await
+                    }
+                    None => self.request(stage, model, &conversation, response_format).await,
+                };
+                let content = match request {
+                    Ok(content) => content,
+                    Err(e) => {
+                        info!("Stage {} failed with model {}: {}", stage.as_ref(), model, e);
+                        last_error = e;
+                        break;
+                    }
+                };
+                match structured::parse::<T>(&content) {
+                    Ok(value) => {
+                        return Ok(Completion { value, model: model.clone() });
+                    }
+                    Err(e) => {
+                        info!(
+                            "Stage {} got an invalid response from model {} (attempt {}): {}",
+                            stage.as_ref(),
+                            model,
+                            attempt + 1,
+                            e
+                        );
+                        conversation.push(json!({ "role": "assistant", "content": content }));
+                        conversation.push(
+                            json!({
+                            "role": "user",
+                            "content": format!(
+                                "Tu respuesta anterior no es válida: {}. Devuelve solo el objeto JSON corregido que cumpla con el esquema, sin otro texto.",
+                                e
+                            )
+                        })
+                        );
+                        last_error = e;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Streams a plain text answer to `messages`, calling `on_token` with each piece
+    /// as it arrives. The next model is only tried if the previous one failed before
+    /// sending anything, so the caller never sees two partial answers.
+    pub async fn stream(
+        &self,
+        stage: Stage,
+        messages: Vec<Value>,
+        mut on_token: impl FnMut(&str)
+    ) -> Result<Completion<String>, String> {
+        let mut last_error = format!("No models configured for stage {}", stage.as_ref());
+        for model in MODELS.models(stage) {
+            if self.over_budget() {
+                return Err("Token budget exceeded".to_string());
+            }
+            let mut streamed = false;
+            let result = self.stream_request(stage, model, &messages, None, |token| {
+                streamed = true;
+                on_token(token);
+            }).await;
+            match result {
+                Ok(value) => {
+                    return Ok(Completion { value, model: model.clone() });
+                }
+                Err(e) => {
+                    info!("Stage {} failed with model {}: {}", stage.as_ref(), model, e);
+                    last_error = e;
+                    if streamed {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn stream_request(
+        &self,
+        stage: Stage,
+        model: &str,
+        messages: &[Value],
+        response_format: Option<Value>,
+        mut on_token: impl FnMut(&str)
+    ) -> Result<String, String> {
+        let mut body =
+            json!({
+            "model": model,
+            "messages": messages,
+            "stream": true,
+            "usage": { "include": true },
+        });
+        if let Some(response_format) = response_format {
+            body["response_format"] = response_format;
+        }
+        let request = HTTP.post(OPENROUTER_URL)
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.api_key))
+            .timeout(MODELS.timeout)
+            .json(&body);
+        let mut response = HTTP.send(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("Request failed with status {}", response.status()));
+        }
+
+        // Server-sent events: one `data: {...}` line per chunk, ending with `data: [DONE]`
+        let mut text = String::new();
+        let mut buffer = Vec::new();
+        while
+            let Some(chunk) = response
+                .chunk().await
+                .map_err(|e| format!("Stream interrupted: {}", e))?
+        {
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    return Ok(text);
+                }
+                let Ok(event) = serde_json::from_str::<Value>(data) else {
+                    continue;
+                };
+                if let Some(error) = event["error"]["message"].as_str() {
+                    return Err(format!("Stream failed: {}", error));
+                }
+                if let Some(token) = event["choices"][0]["delta"]["content"].as_str() && !token.is_empty() {
+                    text.push_str(token);
+                    on_token(token);
+                }
+                if event["usage"].is_object() {
+                    self.record_usage(stage, &parse_usage(&event["usage"]));
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    async fn request(
+        &self,
+        stage: Stage,
+        model: &str,
+        messages: &[Value],
+        response_format: Value
+    ) -> Result<String, String> {
+        let body =
+            json!({
+            "model": model,
+            "messages": messages,
+            "usage": { "include": true },
+            "response_format": response_format
+        });
+        let request = HTTP.post(OPENROUTER_URL)
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.api_key))
+            .timeout(MODELS.timeout)
+            .json(&body);
+        let response = HTTP.send(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("Request failed with status {}", response.status()));
+        }
+        let response_json: Value = response
+            .json().await
+            .map_err(|e| format!("Invalid response body: {}", e))?;
+        self.record_usage(stage, &parse_usage(&response_json["usage"]));
+        response_json["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| "Response has no choices".to_string())
+    }
+}
+
+fn response_format(name: &str, schema: Value) -> Value {
+    json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": name,
+            "strict": true,
+            "schema": structured::strict_schema(&schema)
+        }
+    })
+}
+
+/// Usage of a single request as reported by OpenRouter.
+fn parse_usage(usage: &Value) -> TokenUsage {
+    TokenUsage {
+        requests: 1,
+        prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or_default(),
+        completion_tokens: usage["completion_tokens"].as_u64().unwrap_or_default(),
+        cost: usage["cost"].as_f64().unwrap_or_default(),
+    }
+}
@@ -3,11 +3,26 @@ use dotenv::dotenv;
 use socketioxide::SocketIo;
 use tower::Layer;
 use tracing::info;
-use std::{ env, sync::Arc };
+use std::{ env, net::SocketAddr, sync::{ Arc, LazyLock } };
 use tokio::net::TcpListener;
 use tracing_subscriber::FmtSubscriber;
 use tower_http::{ normalize_path::NormalizePathLayer, cors::CorsLayer };
 
+mod auth;
+mod chat;
+mod config;
+mod duration;
+mod flashcards;
+mod http;
+mod jobs;
+mod llm;
+mod moderation;
+mod progress;
+mod quota;
+mod ratelimit;
+mod readability;
+mod review;
+mod structured;
 mod utils;
 mod routes;
 mod types;
@@ -18,8 +33,10 @@ async fn main() {
     dotenv().ok();
 
     let _ = tracing::subscriber::set_global_default(FmtSubscriber::default());
+    LazyLock::force(&auth::IP_HASH_SALT);
 
     let (layer, io) = SocketIo::new_layer();
+    websocket::IO.set(io.clone()).expect("Socket.io handle already set");
 
     let collections = Arc::new(
         utils::init_database(&io).await.expect("Failed to initialize database")
@@ -40,10 +57,23 @@ async fn main() {
             "/",
             get(|| async { "You're not supposed to be here!" })
         )
-        .nest("/lessons", routes::lessons::get_routes(Arc::clone(&collections)))
+        .nest(
+            "/lessons",
+            routes::lessons
+                ::get_routes(Arc::clone(&collections))
+                .merge(routes::revisions::get_routes(Arc::clone(&collections)))
+                .merge(routes::questions::get_routes(Arc::clone(&collections)))
+                .merge(routes::flashcards::get_routes(Arc::clone(&collections)))
+                .merge(routes::progress::get_routes(Arc::clone(&collections)))
+                .merge(routes::reports::get_routes(Arc::clone(&collections)))
+        )
         .nest("/images", routes::images::get_routes(Arc::clone(&collections)))
         .nest("/tts", routes::tts::get_routes(Arc::clone(&collections)))
         .nest("/museum", routes::museum::get_routes(Arc::clone(&collections)))
+        .nest("/classrooms", routes::classrooms::get_routes(Arc::clone(&collections)))
+        .nest("/me", routes::me::get_routes(Arc::clone(&collections)))
+        .nest("/reviews", routes::reviews::get_routes(Arc::clone(&collections)))
+        .nest("/admin", routes::admin::get_routes(Arc::clone(&collections)))
         .layer(layer)
         .layer(cors);
 
@@ -55,7 +85,10 @@ async fn main() {
     let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await.expect(
         "Failed to bind to port"
     );
-    axum::serve(listener, ServiceExt::<Request>::into_make_service(app)).await.expect(
+    axum::serve(
+        listener,
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app)
+    ).await.expect(
         "Server error"
     );
 }
@@ -0,0 +1,237 @@
+use std::{ env, sync::LazyLock };
+use mongodb::bson::{ doc, oid::ObjectId, DateTime };
+use serde_json::json;
+use tracing::info;
+use crate::{
+    auth::Identity,
+    llm::{ Llm, Stage },
+    structured::ModerationResult,
+    types::{ ModerationAction, ModerationRecord, ModerationVerdict, StatusCodes },
+    utils::{ fold_text, record_user_usage, Collections },
+};
+
+/// Characters of screened text kept in the moderation log.
+const EXCERPT_CHARS: usize = 500;
+/// Characters sent to the model classifier; longer text is cut.
+const MAX_CLASSIFIED_CHARS: usize = 8000;
+
+/// Terms that always block, written without accents. Extended with
+/// `MODERATION_BLOCKLIST`.
+const DEFAULT_BLOCKLIST: &[&str] = &[
+    "pornografia",
+    "porno",
+    "pornography",
+    "porn",
+    "abuso sexual infantil",
+    "child sexual abuse",
+    "fabricar una bomba",
+    "hacer una bomba",
+    "make a bomb",
+    "build a bomb",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Classifier {
+    Off,
+    /// Local term lists only.
+    Blocklist,
+    /// The term lists, then the `moderation` model stage for text they let through.
+    Model,
+}
+
+/// Moderation settings. `MODERATION` picks the classifier (`off`, `blocklist`, the
+/// default, or `model`); `MODERATION_BLOCKLIST` and `MODERATION_FLAGLIST` add comma
+/// separated terms that block or only flag.
+#[derive(Debug, Clone)]
+pub struct Moderation {
+    pub classifier: Classifier,
+    pub blocklist: Vec<String>,
+    pub flaglist: Vec<String>,
+}
+
+pub static MODERATION: LazyLock<Moderation> = LazyLock::new(Moderation::from_env);
+
+/// Whose content is being screened, for the moderation log.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Subject<'a> {
+    pub lesson: Option<ObjectId>,
+    pub user: Option<&'a str>,
+    pub ip_hash: Option<&'a str>,
+}
+
+/// Rejects requests from a banned user id or address.
+pub async fn check_banned(identity: &Identity, collections: &Collections) -> Result<(), StatusCodes> {
+    let mut banned = Vec::new();
+    if let Some(user) = &identity.user {
+        banned.push(doc! { "user": user });
+    }
+    if let Some(ip_hash) = identity.ip_hash() {
+        banned.push(doc! { "ip_hash": ip_hash });
+    }
+    if banned.is_empty() {
+        return Ok(());
+    }
+    match collections.bans.find_one(doc! { "$or": banned }).await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(StatusCodes::Banned),
+        Err(_) => Err(StatusCodes::GenericError),
+    }
+}
+
+fn terms(var: &str) -> Vec<String> {
+    env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(fold_text)
+        .map(|term| term.trim().to_string())
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+/// Whether `term` appears in `text` as whole words. Both must already be folded.
+fn contains_term(text: &str, term: &str) -> bool {
+    text.match_indices(term).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + term.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+impl Moderation {
+    pub fn from_env() -> Self {
+        let classifier = match env::var("MODERATION").unwrap_or_default().trim().to_lowercase().as_str() {
+            "off" => Classifier::Off,
+            "model" => Classifier::Model,
+            _ => Classifier::Blocklist,
+        };
+        let mut blocklist: Vec<String> = DEFAULT_BLOCKLIST.iter()
+            .map(|t| t.to_string())
+            .collect();
+        blocklist.extend(terms("MODERATION_BLOCKLIST"));
+        Moderation { classifier, blocklist, flaglist: terms("MODERATION_FLAGLIST") }
+    }
+
+    fn check_lists(&self, text: &str) -> ModerationVerdict {
+        let text = fold_text(text);
+        let matched = |list: &[String]| list.iter().find(|term| contains_term(&text, term)).cloned();
+        let (action, term) = match matched(&self.blocklist) {
+            Some(term) => (ModerationAction::Block, Some(term)),
+            None => (ModerationAction::Flag, matched(&self.flaglist)),
+        };
+        match term {
+            Some(term) =>
+                ModerationVerdict {
+                    action,
+                    categories: vec!["blocklist".to_string()],
+                    reason: Some(format!("Contiene el término '{}'", term)),
+                    source: "blocklist".to_string(),
+                },
+            None => ModerationVerdict { source: "blocklist".to_string(), ..Default::default() },
+        }
+    }
+
+    /// Whether the term lists alone block `text`. Cheap enough to run on text that is
+    /// still streaming, before the model classifier sees the finished text.
+    pub fn blocks(&self, text: &str) -> bool {
+        self.classifier != Classifier::Off && self.check_lists(text).action == ModerationAction::Block
+    }
+
+    async fn classify(&self, text: &str, llm: &Llm) -> Option<ModerationVerdict> {
+        let text: String = text.chars().take(MAX_CLASSIFIED_CHARS).collect();
+        let prompt = format!(
+            "Eres el moderador de una plataforma educativa para estudiantes de primaria a universidad. Clasifica el siguiente texto. \
+            Usa 'block' para contenido sexual explícito, cualquier contenido sexual que involucre a menores, instrucciones para fabricar armas o drogas o para hacer daño, \
+            incitación a la autolesión, y odio o acoso contra personas o grupos. \
+            Usa 'flag' para contenido que puede ser apropiado en un contexto educativo pero merece revisión, como violencia gráfica, lenguaje ofensivo o temas de sexualidad o drogas. \
+            Usa 'allow' para todo lo demás: los temas difíciles tratados de forma educativa, como guerras, historia, salud o educación sexual escolar, están permitidos. \
+            Devuelve un objeto JSON con 'action', 'categories' (en inglés, por ejemplo 'sexual', 'minors', 'violence', 'self_harm', 'hate', 'harassment', 'weapons', 'drugs') \
+            y 'reason' (una frase en español, vacía si es 'allow'). Solo JSON sin otros textos.\n\nTexto:\n{}",
+            text
+        );
+        match
+            llm.complete::<ModerationResult>(Stage::Moderation, vec![json!({ "role": "user", "content": prompt })]).await
+        {
+            Ok(completion) => {
+                let ModerationResult { action, categories, reason } = completion.value;
+                let reason = Some(reason.trim().to_string()).filter(|r| !r.is_empty());
+                Some(ModerationVerdict { action, categories, reason, source: completion.model })
+            }
+            Err(e) => {
+                info!("Moderation request failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Screens `text` and logs it for moderators unless it is allowed. If the model
+    /// classifier fails the text is allowed, so an outage does not stop every lesson.
+    pub async fn screen(
+        &self,
+        subject: Subject<'_>,
+        target: &str,
+        step: Option<u32>,
+        text: &str,
+        llm: Option<&Llm>,
+        collections: &Collections
+    ) -> ModerationVerdict {
+        if self.classifier == Classifier::Off || text.trim().is_empty() {
+            return ModerationVerdict::default();
+        }
+        let mut verdict = self.check_lists(text);
+        if
+            verdict.action != ModerationAction::Block &&
+            self.classifier == Classifier::Model &&
+            let Some(llm) = llm &&
+            let Some(classified) = self.classify(text, llm).await &&
+            classified.action >= verdict.action
+        {
+            verdict = classified;
+        }
+        if verdict.action != ModerationAction::Allow {
+            let record = ModerationRecord {
+                id: None,
+                lesson: subject.lesson,
+                user: subject.user.map(str::to_string),
+                ip_hash: subject.ip_hash.map(str::to_string),
+                target: target.to_string(),
+                step,
+                excerpt: text.chars().take(EXCERPT_CHARS).collect(),
+                verdict: verdict.clone(),
+                created_at: DateTime::now(),
+            };
+            if let Err(e) = collections.moderation.insert_one(&record).await {
+                info!("Failed to log moderation verdict: {}", e);
+            }
+        }
+        verdict
+    }
+
+    /// Screens text a user submitted, such as a lesson prompt, an edit or a question,
+    /// charging the user for the model classifier. Blocked text comes back as an error;
+    /// flagged text goes through with its verdict.
+    pub async fn screen_request(
+        &self,
+        subject: Subject<'_>,
+        target: &str,
+        step: Option<u32>,
+        text: &str,
+        collections: &Collections
+    ) -> Result<Option<ModerationVerdict>, ModerationVerdict> {
+        let llm = match self.classifier {
+            Classifier::Model => Llm::from_env().ok(),
+            _ => None,
+        };
+        let verdict = self.screen(subject, target, step, text, llm.as_ref(), collections).await;
+        if let Some(llm) = &llm {
+            let usage = llm.take_pending_usage();
+            if usage.requests > 0 && let Err(e) = record_user_usage(subject.user, &usage, collections).await {
+                info!("Failed to record moderation usage: {}", e);
+            }
+        }
+        match verdict.action {
+            ModerationAction::Allow => Ok(None),
+            ModerationAction::Flag => Ok(Some(verdict)),
+            ModerationAction::Block => Err(verdict),
+        }
+    }
+}
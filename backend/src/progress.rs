@@ -0,0 +1,134 @@
+use futures::TryStreamExt;
+use mongodb::{ bson::{ self, doc, oid::ObjectId, DateTime, Document }, options::ReturnDocument };
+use tracing::info;
+use crate::{
+    review,
+    types::{ ProgressUpdate, StatusCodes, UserProgress },
+    utils::Collections,
+    websocket,
+};
+
+/// Longest stretch of time one update can report, so a tab left open overnight does
+/// not count as studying.
+const MAX_SECONDS_PER_UPDATE: u32 = 60 * 60;
+/// Quiz results kept per lesson; older ones are dropped.
+const STORED_QUIZZES: i32 = 100;
+
+/// Adds `update` to `user`'s progress on lesson `lesson_id` and sends the result to
+/// the user's other devices. The lesson counts as completed once every step has been
+/// viewed or the client says so, which also adds it to the user's review queue.
+pub async fn record_progress(
+    lesson_id: &str,
+    user: &str,
+    update: ProgressUpdate,
+    collections: &Collections
+) -> Result<UserProgress, StatusCodes> {
+    let oid = ObjectId::parse_str(lesson_id).map_err(|_| StatusCodes::InvalidID)?;
+    let lesson = collections.lessons
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::LessonNotFound)?;
+    let steps = lesson.outline.len() as u32;
+    let valid_step = |step: Option<u32>| step.is_none_or(|step| step < steps);
+    let valid_quiz = update.quiz
+        .as_ref()
+        .is_none_or(|quiz| quiz.total > 0 && quiz.score <= quiz.total && valid_step(quiz.step));
+    if !valid_step(update.step) || !valid_step(update.listened) || !valid_quiz {
+        return Err(StatusCodes::InvalidNumber);
+    }
+    let seconds = update.seconds.unwrap_or(0);
+    if seconds > MAX_SECONDS_PER_UPDATE {
+        return Err(StatusCodes::InvalidNumber);
+    }
+
+    let mut changes = doc! {
+        "$set": { "updated_at": DateTime::now() },
+        "$inc": { "time_spent": i64::from(seconds) },
+        "$setOnInsert": { "completed": false, "completed_at": null },
+    };
+    let mut added = Document::new();
+    if let Some(step) = update.step {
+        changes.get_document_mut("$set").unwrap().insert("current_step", step);
+        added.insert("viewed", step);
+    }
+    if let Some(step) = update.listened {
+        added.insert("listened", step);
+    }
+    if !added.is_empty() {
+        changes.insert("$addToSet", added);
+    }
+    if let Some(quiz) = &update.quiz {
+        changes.insert(
+            "$push",
+            doc! { "quizzes": { "$each": [bson::to_bson(quiz).unwrap()], "$slice": -STORED_QUIZZES } }
+        );
+    }
+    let mut progress = collections.progress
+        .find_one_and_update(doc! { "user": user, "lesson": oid }, changes)
+        .upsert(true)
+        .return_document(ReturnDocument::After).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::GenericError)?;
+
+    let all_viewed = steps > 0 && (0..steps).all(|step| progress.viewed.contains(&step));
+    if !progress.completed && (update.completed || all_viewed) {
+        // Only the first update to complete the lesson sets the completion time
+        let completed = collections.progress
+            .find_one_and_update(
+                doc! { "user": user, "lesson": oid, "completed": false },
+                doc! { "$set": { "completed": true, "completed_at": DateTime::now() } }
+            )
+            .return_document(ReturnDocument::After).await
+            .map_err(|_| StatusCodes::GenericError)?;
+        if let Some(completed) = completed {
+            progress = completed;
+            if let Err(status) = review::enroll_lesson(user, oid, collections).await {
+                info!("Failed to add lesson {} to the review queue of {}: {:?}", lesson_id, user, status);
+            }
+        }
+    }
+    websocket::emit_progress(user, &progress).await;
+    Ok(progress)
+}
+
+/// `user`'s progress on lesson `lesson_id`, if they have opened it.
+pub async fn get_progress(
+    lesson_id: &str,
+    user: &str,
+    collections: &Collections
+) -> Result<Option<UserProgress>, StatusCodes> {
+    let oid = ObjectId::parse_str(lesson_id).map_err(|_| StatusCodes::InvalidID)?;
+    collections.progress
+        .find_one(doc! { "user": user, "lesson": oid }).await
+        .map_err(|_| StatusCodes::GenericError)
+}
+
+/// `user`'s progress on every lesson, most recent first, with each lesson's title and
+/// step count so a client can offer to resume without loading the lessons.
+pub async fn list_progress(user: &str, collections: &Collections) -> Result<Vec<Document>, StatusCodes> {
+    let pipeline = vec![
+        doc! { "$match": { "user": user } },
+        doc! { "$sort": { "updated_at": -1 } },
+        doc! {
+            "$lookup": {
+                "from": collections.lessons.name(),
+                "localField": "lesson",
+                "foreignField": "_id",
+                "as": "lesson_info",
+                "pipeline": [{ "$project": { "title": 1, "steps": { "$size": { "$ifNull": ["$outline", []] } } } }],
+            },
+        },
+        doc! {
+            "$set": {
+                "title": { "$first": "$lesson_info.title" },
+                "steps": { "$first": "$lesson_info.steps" },
+            },
+        },
+        doc! { "$unset": "lesson_info" }
+    ];
+    collections.progress
+        .aggregate(pipeline).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .try_collect().await
+        .map_err(|_| StatusCodes::GenericError)
+}
@@ -0,0 +1,59 @@
+use std::sync::LazyLock;
+use mongodb::bson::{ doc, DateTime, Document };
+use tracing::info;
+use crate::{ auth::Identity, config::env_number, moderation, types::StatusCodes, utils::Collections };
+
+/// Limits on lesson generation. Rates are checked when a lesson is requested; step
+/// and token limits are enforced by the pipeline while it runs.
+#[derive(Debug, Clone)]
+pub struct QuotaConfig {
+    /// Keyed by the `X-User-Id` header, which clients choose freely, so this only
+    /// holds back honest clients; `lessons_per_hour_per_ip` is the limit that holds.
+    pub lessons_per_hour_per_user: u64,
+    pub lessons_per_hour_per_ip: u64,
+    pub max_steps_per_lesson: usize,
+    pub max_tokens_per_lesson: u64,
+}
+
+impl QuotaConfig {
+    /// A limit of `0` disables that check.
+    pub fn from_env() -> Self {
+        QuotaConfig {
+            lessons_per_hour_per_user: env_number("LESSONS_PER_HOUR_PER_USER", 10),
+            lessons_per_hour_per_ip: env_number("LESSONS_PER_HOUR_PER_IP", 20),
+            max_steps_per_lesson: env_number("MAX_STEPS_PER_LESSON", 12),
+            max_tokens_per_lesson: env_number("MAX_TOKENS_PER_LESSON", 200_000),
+        }
+    }
+}
+
+pub static QUOTAS: LazyLock<QuotaConfig> = LazyLock::new(QuotaConfig::from_env);
+
+/// Checks whether `identity` may start another lesson: it must not be banned, and
+/// the lessons it requested in the last hour are counted by user id and by address.
+/// Only the address count holds against a client that changes its user id.
+pub async fn check_lesson_rate(identity: &Identity, collections: &Collections) -> Result<(), StatusCodes> {
+    moderation::check_banned(identity, collections).await?;
+    let since = DateTime::from_millis(DateTime::now().timestamp_millis() - 60 * 60 * 1000);
+    let checks = [
+        ("user", identity.user.clone(), QUOTAS.lessons_per_hour_per_user),
+        ("ip_hash", identity.ip_hash(), QUOTAS.lessons_per_hour_per_ip),
+    ];
+    for (field, value, limit) in checks {
+        let Some(value) = value else {
+            continue;
+        };
+        if limit == 0 {
+            continue;
+        }
+        let filter: Document = doc! { field: &value, "created_at": { "$gte": since } };
+        let count = collections.lessons
+            .count_documents(filter).await
+            .map_err(|_| StatusCodes::GenericError)?;
+        if count >= limit {
+            info!("Lesson quota exceeded for {} {}", field, value);
+            return Err(StatusCodes::QuotaExceeded);
+        }
+    }
+    Ok(())
+}
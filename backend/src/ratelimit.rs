@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{ Arc, Mutex },
+    task::{ Context, Poll },
+    time::Instant,
+};
+use axum::{
+    extract::{ ConnectInfo, Request },
+    http::{ Extensions, HeaderMap, HeaderValue, StatusCode },
+    response::{ IntoResponse, Response },
+    Json,
+};
+use futures::future::BoxFuture;
+use serde_json::json;
+use tower::{ Layer, Service };
+use crate::{ auth::{ client_ip, parse_user_id }, config::env_number, types::StatusCodes };
+
+/// Buckets that have refilled completely are dropped once this many are tracked.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// A token bucket holding up to `burst` requests, refilled at `per_minute`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Limit {
+    /// Reads `RATE_LIMIT_<NAME>` as requests per minute, which is also the burst size.
+    pub fn from_env(name: &str, default: u32) -> Self {
+        let per_minute = match env_number(&format!("RATE_LIMIT_{}", name), default) {
+            0 => default,
+            n => n,
+        };
+        Limit { burst: per_minute, per_minute }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        (self.per_minute as f64) / 60.0
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Outcome of taking a token, with what goes into the `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy)]
+struct Decision {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Seconds until the next request would be allowed.
+    retry_after: u64,
+}
+
+#[derive(Debug)]
+struct Limiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    /// Checks every key of a request; it goes through only if all of them have tokens.
+    fn decide(&self, headers: &HeaderMap, extensions: &Extensions) -> Decision {
+        self.take(&keys(headers, extensions))
+    }
+
+    /// Takes a token from the bucket of every key in `keys`, or from none of them if
+    /// any is empty, so a request denied by one bucket does not drain the others.
+    fn take(&self, keys: &[String]) -> Decision {
+        let now = Instant::now();
+        let rate = self.limit.refill_rate();
+        let burst = self.limit.burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+        }
+        for key in keys {
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert(Bucket { tokens: burst, updated: now });
+            bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+            bucket.updated = now;
+        }
+        let allowed = keys.iter().all(|key| buckets[key].tokens >= 1.0);
+        keys.iter()
+            .map(|key| {
+                let bucket = buckets.get_mut(key).expect("Bucket was created above");
+                if allowed {
+                    bucket.tokens -= 1.0;
+                }
+                Decision {
+                    allowed,
+                    limit: self.limit.burst,
+                    remaining: bucket.tokens.floor() as u32,
+                    reset: ((burst - bucket.tokens) / rate).ceil() as u64,
+                    retry_after: ((1.0 - bucket.tokens).max(0.0) / rate).ceil() as u64,
+                }
+            })
+            .reduce(|a, b| Decision {
+                allowed,
+                limit: a.limit,
+                remaining: a.remaining.min(b.remaining),
+                reset: a.reset.max(b.reset),
+                retry_after: a.retry_after.max(b.retry_after),
+            })
+            .expect("Every request has at least one rate limit key")
+    }
+}
+
+/// Token bucket rate limiting for a route. Every client is limited by address (as
+/// seen through trusted proxies); requests that carry an `X-User-Id` are also
+/// limited per user, so changing the id does not get around the address limit.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limit: Limit) -> Self {
+        RateLimitLayer {
+            limiter: Arc::new(Limiter { limit, buckets: Mutex::new(HashMap::new()) }),
+        }
+    }
+
+    /// Takes a token for something that does not go through the HTTP layer, such as a
+    /// socket event, from the same buckets as the routes this layer guards.
+    pub fn allow(&self, headers: &HeaderMap, extensions: &Extensions) -> bool {
+        self.limiter.decide(headers, extensions).allowed
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit { inner, limiter: Arc::clone(&self.limiter) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+    where
+        S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+        S::Future: Send + 'static
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let decision = self.limiter.decide(request.headers(), request.extensions());
+        // Call the clone that was driven to readiness and keep a fresh one for next time
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let mut response = if decision.allowed {
+                inner.call(request).await?
+            } else {
+                let mut response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Json(json!({"status": StatusCodes::RateLimited})),
+                ).into_response();
+                response.headers_mut().insert("retry-after", HeaderValue::from(decision.retry_after));
+                response
+            };
+            set_headers(response.headers_mut(), &decision);
+            Ok(response)
+        })
+    }
+}
+
+fn keys(headers: &HeaderMap, extensions: &Extensions) -> Vec<String> {
+    let ip = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| client_ip(c.0.ip(), headers));
+    let user = headers
+        .get("x-user-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_user_id);
+    let mut keys = vec![match ip {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_string(),
+    }];
+    if let Some(user) = user {
+        keys.push(format!("user:{}", user));
+    }
+    keys
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(decision.reset));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn limiter(burst: u32) -> Limiter {
+        Limiter { limit: Limit { burst, per_minute: burst }, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_a_burst_then_denies() {
+        let limiter = limiter(3);
+        let ip = keys(&["ip:1"]);
+        for remaining in [2, 1, 0] {
+            let decision = limiter.take(&ip);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = limiter.take(&ip);
+        assert!(!denied.allowed);
+        // One token comes back every 20 seconds at 3 per minute
+        assert_eq!(denied.retry_after, 20);
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_burst() {
+        let limiter = limiter(3);
+        let ip = keys(&["ip:1"]);
+        for _ in 0..3 {
+            limiter.take(&ip);
+        }
+        let rewind = |seconds| {
+            let mut buckets = limiter.buckets.lock().unwrap();
+            let bucket = buckets.get_mut("ip:1").unwrap();
+            bucket.updated -= Duration::from_secs(seconds);
+        };
+        rewind(20);
+        assert!(limiter.take(&ip).allowed);
+        assert!(!limiter.take(&ip).allowed);
+        rewind(3600);
+        assert_eq!(limiter.take(&ip).remaining, 2);
+    }
+
+    #[test]
+    fn denied_requests_take_from_no_bucket() {
+        let limiter = limiter(2);
+        let user = keys(&["ip:1", "user:a"]);
+        // Another device of the same user spends the user bucket from a different address
+        limiter.take(&keys(&["ip:2", "user:a"]));
+        limiter.take(&keys(&["ip:2", "user:a"]));
+        assert!(!limiter.take(&user).allowed);
+        assert_eq!(limiter.buckets.lock().unwrap()["ip:1"].tokens, 2.0);
+        assert!(limiter.take(&keys(&["ip:1"])).allowed);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::types::{ Difficulty, Readability };
+
+/// Explanations shorter than this are not scored, since a couple of sentences swing
+/// the formulas too much to mean anything.
+pub const MIN_WORDS: usize = 30;
+
+const SPANISH_STOPWORDS: [&str; 12] = ["el", "la", "los", "las", "de", "del", "que", "y", "en", "un", "una", "es"];
+const ENGLISH_STOPWORDS: [&str; 12] = ["the", "of", "and", "to", "in", "is", "that", "a", "an", "it", "are", "for"];
+
+/// Range of the main score (Szigriszt-Pazos for Spanish, Flesch reading ease for
+/// English; both run from about 0, very hard, to 100, very easy) that text for
+/// `difficulty` should fall in. Very short sentences can score above 100, which is
+/// never too easy for elementary students.
+pub fn target_band(difficulty: &Difficulty) -> (f64, f64) {
+    match difficulty {
+        Difficulty::Elementary => (65.0, f64::INFINITY),
+        Difficulty::HighSchool => (50.0, 80.0),
+        Difficulty::University => (25.0, 65.0),
+    }
+}
+
+/// How far `score` is from the band for `difficulty`, 0 inside it.
+pub fn distance(score: f64, difficulty: &Difficulty) -> f64 {
+    let (low, high) = target_band(difficulty);
+    (low - score).max(score - high).max(0.0)
+}
+
+/// Scores `text` for a reader at `difficulty`, or `None` if it is too short to
+/// score. Math between `$` and markdown markers are ignored.
+pub fn assess(text: &str, difficulty: &Difficulty) -> Option<Readability> {
+    let text = plain_text(text);
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().any(|c| c.is_alphabetic()))
+        .collect();
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+    let sentences = text
+        .split(['.', '!', '?', ';', ':', '\n'])
+        .filter(|s| s.chars().any(|c| c.is_alphabetic()))
+        .count()
+        .max(1) as f64;
+    let english = is_english(&words);
+    let syllables: usize = words
+        .iter()
+        .map(|w| if english { english_syllables(w) } else { spanish_syllables(w) })
+        .sum();
+    let word_count = words.len() as f64;
+    let syllables_per_word = (syllables as f64) / word_count;
+    let words_per_sentence = word_count / sentences;
+
+    let (language, score, fernandez_huerta) = if english {
+        ("en", 206.835 - 1.015 * words_per_sentence - 84.6 * syllables_per_word, None)
+    } else {
+        let szigriszt_pazos = 206.835 - 62.3 * syllables_per_word - words_per_sentence;
+        let fernandez_huerta = 206.84 - 60.0 * syllables_per_word - 102.0 / words_per_sentence;
+        ("es", szigriszt_pazos, Some(round(fernandez_huerta)))
+    };
+    Some(Readability {
+        language: language.to_string(),
+        score: round(score),
+        fernandez_huerta,
+        in_band: distance(score, difficulty) == 0.0,
+        rewrites: 0,
+    })
+}
+
+fn round(score: f64) -> f64 {
+    (score * 10.0).round() / 10.0
+}
+
+/// Drops `$...$` math and markdown emphasis, turning list items into sentences.
+fn plain_text(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut in_math = false;
+    for c in text.chars() {
+        match c {
+            '$' => {
+                in_math = !in_math;
+            }
+            _ if in_math => {}
+            '*' | '_' | '#' | '`' => {}
+            _ => plain.push(c),
+        }
+    }
+    plain
+        .lines()
+        .map(|line| line.trim_start_matches(['-', ' ']))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn is_english(words: &[&str]) -> bool {
+    let count = |stopwords: &[&str]| {
+        words
+            .iter()
+            .filter(|w| stopwords.contains(&w.to_lowercase().as_str()))
+            .count()
+    };
+    count(&ENGLISH_STOPWORDS) > count(&SPANISH_STOPWORDS)
+}
+
+/// Counts Spanish syllables as vowel groups, splitting a group where two strong
+/// vowels (`a`, `e`, `o`) meet or where a stressed `í` or `ú` breaks a diphthong.
+fn spanish_syllables(word: &str) -> usize {
+    fn vowel(c: char) -> Option<bool> {
+        // Whether the vowel keeps its own syllable next to another one
+        match c {
+            'a' | 'e' | 'o' | 'á' | 'é' | 'ó' | 'í' | 'ú' => Some(true),
+            'i' | 'u' | 'ü' => Some(false),
+            _ => None,
+        }
+    }
+    let mut syllables = 0;
+    let mut previous: Option<bool> = None;
+    for c in word.to_lowercase().chars() {
+        let current = vowel(c);
+        match (previous, current) {
+            (None, Some(_)) => {
+                syllables += 1;
+            }
+            (Some(true), Some(true)) => {
+                syllables += 1;
+            }
+            _ => {}
+        }
+        previous = current;
+    }
+    syllables.max(1)
+}
+
+/// Counts English syllables as vowel groups, not counting a silent final `e`.
+fn english_syllables(word: &str) -> usize {
+    let word = word.to_lowercase();
+    let mut syllables = 0;
+    let mut previous = false;
+    for c in word.chars() {
+        let current = "aeiouy".contains(c);
+        if current && !previous {
+            syllables += 1;
+        }
+        previous = current;
+    }
+    if word.ends_with('e') && !word.ends_with("le") && syllables > 1 {
+        syllables -= 1;
+    }
+    syllables.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spanish_syllables_split_hiatus_and_keep_diphthongs() {
+        let cases = [
+            ("gato", 2),
+            ("ciudad", 2),
+            ("aire", 2),
+            ("huevo", 2),
+            ("poeta", 3),
+            ("leer", 2),
+            ("día", 2),
+            ("país", 2),
+            ("búho", 2),
+            ("pingüino", 3),
+            ("y", 1),
+        ];
+        for (word, syllables) in cases {
+            assert_eq!(spanish_syllables(word), syllables, "{}", word);
+        }
+    }
+
+    #[test]
+    fn english_syllables_drop_a_silent_e() {
+        let cases = [("cat", 1), ("ate", 1), ("the", 1), ("table", 2), ("information", 4), ("technology", 4)];
+        for (word, syllables) in cases {
+            assert_eq!(english_syllables(word), syllables, "{}", word);
+        }
+    }
+
+    #[test]
+    fn distance_to_the_band() {
+        assert_eq!(distance(120.0, &Difficulty::Elementary), 0.0);
+        assert_eq!(distance(60.0, &Difficulty::Elementary), 5.0);
+        assert_eq!(distance(40.0, &Difficulty::HighSchool), 10.0);
+        assert_eq!(distance(90.0, &Difficulty::HighSchool), 10.0);
+        assert_eq!(distance(25.0, &Difficulty::University), 0.0);
+    }
+
+    #[test]
+    fn scores_spanish_text() {
+        // 32 words, 48 syllables and 8 sentences, with math and markdown ignored
+        let text = "El **gato** come pan $x^2$.\n".repeat(8);
+        let readability = assess(&text, &Difficulty::Elementary).unwrap();
+        assert_eq!(readability.language, "es");
+        assert_eq!(readability.score, 109.4);
+        assert_eq!(readability.fernandez_huerta, Some(91.3));
+        assert!(readability.in_band);
+        assert!(!assess(&text, &Difficulty::University).unwrap().in_band);
+    }
+
+    #[test]
+    fn scores_english_text() {
+        // 32 words, 96 syllables and 8 sentences
+        let text = "Information technology is important. ".repeat(8);
+        let readability = assess(&text, &Difficulty::University).unwrap();
+        assert_eq!(readability.language, "en");
+        assert_eq!(readability.score, -51.0);
+        assert_eq!(readability.fernandez_huerta, None);
+        assert!(!readability.in_band);
+    }
+
+    #[test]
+    fn short_text_is_not_scored() {
+        assert!(assess("El gato come pan.", &Difficulty::Elementary).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+use futures::TryStreamExt;
+use mongodb::bson::{ doc, oid::ObjectId, DateTime, Document };
+use crate::{ types::{ LessonStatus, ReviewItem, StatusCodes }, utils::Collections };
+
+const INITIAL_EASE: f64 = 2.5;
+const MIN_EASE: f64 = 1.3;
+const MAX_GRADE: u8 = 5;
+/// Grades below this count as forgotten.
+const PASSING_GRADE: u8 = 3;
+/// Items whose interval reaches this many days are considered mature.
+const MATURE_INTERVAL: u32 = 21;
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+/// Applies an SM-2 review with `grade` at `now`: a pass grows the interval (1 day, 6
+/// days, then by the ease factor), a fail starts the item over the next day. The ease
+/// factor moves with every grade and never drops below 1.3.
+pub fn schedule(item: &mut ReviewItem, grade: u8, now: DateTime) {
+    let quality = f64::from(grade.min(MAX_GRADE));
+    if grade >= PASSING_GRADE {
+        item.interval = match item.repetitions {
+            0 => 1,
+            1 => 6,
+            _ => ((item.interval as f64) * item.ease).round() as u32,
+        };
+        item.repetitions += 1;
+    } else {
+        if item.repetitions > 0 {
+            item.lapses += 1;
+        }
+        item.repetitions = 0;
+        item.interval = 1;
+    }
+    let miss = f64::from(MAX_GRADE) - quality;
+    item.ease = (item.ease + 0.1 - miss * (0.08 + miss * 0.02)).max(MIN_EASE);
+    item.reviews += 1;
+    item.last_reviewed = Some(now);
+    item.due = DateTime::from_millis(now.timestamp_millis() + i64::from(item.interval) * DAY_MILLIS);
+}
+
+/// Adds every generated step of lesson `lesson` to `user`'s review queue, due now.
+/// Steps already in the queue keep their schedule and only get their text refreshed,
+/// so enrolling a lesson again is harmless. Returns how many steps were added.
+pub async fn enroll_lesson(user: &str, lesson: ObjectId, collections: &Collections) -> Result<u64, StatusCodes> {
+    let record = collections.lessons
+        .find_one(doc! { "_id": lesson }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::LessonNotFound)?;
+    if record.status != LessonStatus::Completed {
+        return Err(StatusCodes::InvalidState);
+    }
+    let now = DateTime::now();
+    let mut added = 0;
+    for (i, step) in record.steps.iter().enumerate() {
+        let Some(step) = step else {
+            continue;
+        };
+        let result = collections.reviews
+            .update_one(
+                doc! { "user": user, "lesson": lesson, "step": i as u32 },
+                doc! {
+                    "$set": { "front": &step.title, "back": &step.explanation },
+                    "$setOnInsert": {
+                        "ease": INITIAL_EASE,
+                        "interval": 0,
+                        "repetitions": 0,
+                        "lapses": 0,
+                        "reviews": 0,
+                        "due": now,
+                        "last_reviewed": null,
+                        "created_at": now,
+                    },
+                }
+            )
+            .upsert(true).await
+            .map_err(|_| StatusCodes::GenericError)?;
+        if result.upserted_id.is_some() {
+            added += 1;
+        }
+    }
+    Ok(added)
+}
+
+/// `user`'s items that are due, oldest first.
+pub async fn due_items(user: &str, limit: i64, collections: &Collections) -> Result<Vec<ReviewItem>, StatusCodes> {
+    collections.reviews
+        .find(doc! { "user": user, "due": { "$lte": DateTime::now() } })
+        .sort(doc! { "due": 1 })
+        .limit(limit).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .try_collect().await
+        .map_err(|_| StatusCodes::GenericError)
+}
+
+/// Grades one of `user`'s items and stores its new schedule.
+pub async fn grade_item(
+    user: &str,
+    item_id: &str,
+    grade: u8,
+    collections: &Collections
+) -> Result<ReviewItem, StatusCodes> {
+    if grade > MAX_GRADE {
+        return Err(StatusCodes::InvalidNumber);
+    }
+    let oid = ObjectId::parse_str(item_id).map_err(|_| StatusCodes::InvalidID)?;
+    let mut item = collections.reviews
+        .find_one(doc! { "_id": oid, "user": user }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::InvalidID)?;
+    let reviews = item.reviews;
+    schedule(&mut item, grade, DateTime::now());
+    // Matching on the review count makes a grade submitted twice only count once
+    let result = collections.reviews
+        .update_one(
+            doc! { "_id": oid, "user": user, "reviews": reviews },
+            doc! {
+                "$set": {
+                    "ease": item.ease,
+                    "interval": item.interval,
+                    "repetitions": item.repetitions,
+                    "lapses": item.lapses,
+                    "reviews": item.reviews,
+                    "due": item.due,
+                    "last_reviewed": item.last_reviewed,
+                },
+            }
+        ).await
+        .map_err(|_| StatusCodes::GenericError)?;
+    if result.matched_count == 0 {
+        return Err(StatusCodes::InvalidState);
+    }
+    Ok(item)
+}
+
+/// Counts of `user`'s items by state, with how many were reviewed today (UTC) and how
+/// many are due by the end of it.
+pub async fn stats(user: &str, collections: &Collections) -> Result<Document, StatusCodes> {
+    let now = DateTime::now();
+    let day_start = now.timestamp_millis() - now.timestamp_millis().rem_euclid(DAY_MILLIS);
+    let today = DateTime::from_millis(day_start);
+    let tomorrow = DateTime::from_millis(day_start + DAY_MILLIS);
+    let count_if = |condition: Document| doc! { "$sum": { "$cond": [condition, 1, 0] } };
+    let pipeline = vec![
+        doc! { "$match": { "user": user } },
+        doc! {
+            "$group": {
+                "_id": null,
+                "total": { "$sum": 1 },
+                "lessons": { "$addToSet": "$lesson" },
+                "new": count_if(doc! { "$eq": ["$reviews", 0] }),
+                "mature": count_if(doc! { "$gte": ["$interval", MATURE_INTERVAL] }),
+                "due_now": count_if(doc! { "$lte": ["$due", now] }),
+                "due_today": count_if(doc! { "$lt": ["$due", tomorrow] }),
+                "reviewed_today": count_if(doc! { "$gte": ["$last_reviewed", today] }),
+                "reviews": { "$sum": "$reviews" },
+                "lapses": { "$sum": "$lapses" },
+                "average_ease": { "$avg": "$ease" },
+            },
+        },
+        doc! { "$set": { "lessons": { "$size": "$lessons" } } },
+        doc! { "$unset": "_id" }
+    ];
+    let results: Vec<Document> = collections.reviews
+        .aggregate(pipeline).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .try_collect().await
+        .map_err(|_| StatusCodes::GenericError)?;
+    Ok(
+        results.into_iter().next().unwrap_or_else(|| {
+            doc! {
+                "total": 0,
+                "lessons": 0,
+                "new": 0,
+                "mature": 0,
+                "due_now": 0,
+                "due_today": 0,
+                "reviewed_today": 0,
+                "reviews": 0,
+                "lapses": 0,
+                "average_ease": null,
+            }
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> ReviewItem {
+        let now = DateTime::now();
+        ReviewItem {
+            id: None,
+            user: "user".into(),
+            lesson: ObjectId::new(),
+            step: 0,
+            front: String::new(),
+            back: String::new(),
+            ease: INITIAL_EASE,
+            interval: 0,
+            repetitions: 0,
+            lapses: 0,
+            reviews: 0,
+            due: now,
+            last_reviewed: None,
+            created_at: now,
+        }
+    }
+
+    #[test]
+    fn passing_grades_grow_the_interval() {
+        let mut item = item();
+        let now = DateTime::now();
+        let intervals: Vec<u32> = (0..4)
+            .map(|_| {
+                schedule(&mut item, 4, now);
+                item.interval
+            })
+            .collect();
+        // A 4 leaves the ease at 2.5, so after 1 and 6 days the interval grows 2.5 times
+        assert_eq!(intervals, [1, 6, 15, 38]);
+        assert_eq!(item.ease, INITIAL_EASE);
+        assert_eq!(item.repetitions, 4);
+        assert_eq!(item.due.timestamp_millis(), now.timestamp_millis() + 38 * DAY_MILLIS);
+        schedule(&mut item, 5, now);
+        assert!((item.ease - 2.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn failing_grade_starts_over() {
+        let mut item = item();
+        let now = DateTime::now();
+        for _ in 0..3 {
+            schedule(&mut item, 5, now);
+        }
+        schedule(&mut item, 2, now);
+        assert_eq!((item.interval, item.repetitions, item.lapses, item.reviews), (1, 0, 1, 4));
+        assert_eq!(item.due.timestamp_millis(), now.timestamp_millis() + DAY_MILLIS);
+        // Failing an item that was never learned is not a lapse
+        schedule(&mut item, 0, now);
+        assert_eq!(item.lapses, 1);
+        schedule(&mut item, 3, now);
+        assert_eq!((item.interval, item.repetitions), (1, 1));
+    }
+
+    #[test]
+    fn ease_never_drops_below_the_floor() {
+        let mut item = item();
+        let now = DateTime::now();
+        schedule(&mut item, 0, now);
+        assert!((item.ease - 1.7).abs() < 1e-9);
+        schedule(&mut item, 0, now);
+        assert_eq!(item.ease, MIN_EASE);
+        schedule(&mut item, 3, now);
+        assert_eq!(item.ease, MIN_EASE);
+    }
+}
@@ -2,3 +2,12 @@ pub mod lessons;
 pub mod images;
 pub mod tts;
 pub mod museum;
+pub mod admin;
+pub mod revisions;
+pub mod questions;
+pub mod flashcards;
+pub mod reviews;
+pub mod progress;
+pub mod me;
+pub mod classrooms;
+pub mod reports;
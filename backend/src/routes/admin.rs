@@ -0,0 +1,470 @@
+use std::sync::Arc;
+
+use axum::{ extract::{ self, Path, Query }, response::IntoResponse, routing::{ delete, get, post }, Json, Router };
+use futures::TryStreamExt;
+use mongodb::bson::{ self, doc, oid::ObjectId, DateTime, Document };
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    auth::{ parse_user_id, Admin },
+    jobs,
+    types::{ Ban, NewBan, ReportStatus, StatusCodes },
+    utils::Collections,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// How many days back to include, counting today.
+    #[serde(default = "default_days")]
+    pub days: u32,
+    /// How many users to list in `top_spenders`.
+    #[serde(default = "default_top")]
+    pub top: u32,
+}
+
+fn default_days() -> u32 {
+    30
+}
+
+fn default_top() -> u32 {
+    10
+}
+
+fn usage_sums() -> Document {
+    doc! {
+        "requests": { "$sum": "$requests" },
+        "prompt_tokens": { "$sum": "$prompt_tokens" },
+        "completion_tokens": { "$sum": "$completion_tokens" },
+        "cost": { "$sum": "$cost" },
+    }
+}
+
+async fn aggregate(collections: &Collections, pipeline: Vec<Document>) -> mongodb::error::Result<Vec<Document>> {
+    collections.usage.aggregate(pipeline).await?.try_collect().await
+}
+
+pub async fn get_usage(
+    _admin: Admin,
+    Query(query): Query<UsageQuery>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    if query.days == 0 {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let since = (chrono::Utc::now() - chrono::Duration::days(i64::from(query.days) - 1))
+        .format("%Y-%m-%d")
+        .to_string();
+    let matched = doc! { "$match": { "day": { "$gte": &since } } };
+
+    let mut totals = doc! { "_id": null };
+    totals.extend(usage_sums());
+    let mut per_day = doc! { "_id": "$day" };
+    per_day.extend(usage_sums());
+    let mut per_user = doc! { "_id": "$user", "days": { "$sum": 1 } };
+    per_user.extend(usage_sums());
+
+    let results = futures::try_join!(
+        aggregate(collections, vec![matched.clone(), doc! { "$group": totals }, doc! { "$unset": "_id" }]),
+        aggregate(
+            collections,
+            vec![
+                matched.clone(),
+                doc! { "$group": per_day },
+                doc! { "$sort": { "_id": 1 } },
+                doc! { "$set": { "day": "$_id" } },
+                doc! { "$unset": "_id" }
+            ]
+        ),
+        aggregate(
+            collections,
+            vec![
+                matched,
+                doc! { "$match": { "user": { "$ne": null } } },
+                doc! { "$group": per_user },
+                doc! { "$sort": { "cost": -1 } },
+                doc! { "$limit": i64::from(query.top) },
+                doc! { "$set": { "user": "$_id" } },
+                doc! { "$unset": "_id" }
+            ]
+        )
+    );
+    match results {
+        Ok((totals, per_day, top_spenders)) =>
+            Json(
+                json!({
+                "status": StatusCodes::Success,
+                "since": since,
+                "totals": totals.into_iter().next().unwrap_or_else(|| {
+                    doc! { "requests": 0, "prompt_tokens": 0, "completion_tokens": 0, "cost": 0.0 }
+                }),
+                "per_day": per_day,
+                "top_spenders": top_spenders,
+            })
+            ),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    #[serde(default)]
+    pub status: ReportStatus,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    50
+}
+
+/// The moderation queue: reports with the given status, newest first, each with a
+/// summary of the reported lesson, and the lessons moderation flagged that are still
+/// hidden.
+pub async fn get_reports(
+    _admin: Admin,
+    Query(query): Query<ReportQuery>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    if query.limit == 0 {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let summary = doc! {
+        "title": 1,
+        "user": 1,
+        "ip_hash": 1,
+        "status": 1,
+        "hidden": 1,
+        "moderation": 1,
+        "created_at": 1,
+    };
+    let reports = async {
+        collections.reports
+            .aggregate(
+                vec![
+                    doc! { "$match": { "status": bson::to_bson(&query.status).unwrap() } },
+                    doc! { "$sort": { "created_at": -1 } },
+                    doc! { "$limit": i64::from(query.limit) },
+                    doc! {
+                        "$lookup": {
+                            "from": collections.lessons.name(),
+                            "localField": "lesson",
+                            "foreignField": "_id",
+                            "pipeline": [{ "$project": summary.clone() }],
+                            "as": "lesson_summary",
+                        }
+                    },
+                    doc! { "$set": { "lesson_summary": { "$first": "$lesson_summary" } } }
+                ]
+            ).await?
+            .try_collect::<Vec<Document>>().await
+    };
+    let flagged = async {
+        collections.lessons
+            .clone_with_type::<Document>()
+            .find(doc! { "hidden": { "$ne": false }, "moderation.action": "flag" })
+            .projection(summary.clone())
+            .sort(doc! { "created_at": -1 })
+            .limit(i64::from(query.limit)).await?
+            .try_collect::<Vec<Document>>().await
+    };
+    match futures::try_join!(reports, flagged) {
+        Ok((reports, flagged)) => Json(json!({"status": StatusCodes::Success, "reports": reports, "flagged": flagged})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Closes the open reports on a lesson.
+async fn close_reports(lesson: ObjectId, status: ReportStatus, collections: &Collections) {
+    let result = collections.reports
+        .update_many(
+            doc! { "lesson": lesson, "status": bson::to_bson(&ReportStatus::Open).unwrap() },
+            doc! { "$set": { "status": bson::to_bson(&status).unwrap(), "resolved_at": DateTime::now() } }
+        ).await;
+    if let Err(e) = result {
+        info!("Failed to close reports for lesson {}: {}", lesson, e);
+    }
+}
+
+/// Marks a report as needing no action.
+pub async fn dismiss_report(
+    _admin: Admin,
+    Path(id): Path<String>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let result = collections.reports
+        .update_one(
+            doc! { "_id": oid },
+            doc! {
+                "$set": {
+                    "status": bson::to_bson(&ReportStatus::Dismissed).unwrap(),
+                    "resolved_at": DateTime::now(),
+                }
+            }
+        ).await;
+    match result {
+        Ok(result) if result.matched_count == 1 => Json(json!({"status": StatusCodes::Success})),
+        Ok(_) => Json(json!({"status": StatusCodes::InvalidID})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Hides or shows a lesson in the museum gallery. Hiding resolves its open reports.
+async fn set_hidden(id: &str, hidden: bool, collections: &Collections) -> StatusCodes {
+    let Ok(oid) = ObjectId::parse_str(id) else {
+        return StatusCodes::InvalidID;
+    };
+    match collections.lessons.update_one(doc! { "_id": oid }, doc! { "$set": { "hidden": hidden } }).await {
+        Ok(result) if result.matched_count == 1 => {
+            if hidden {
+                close_reports(oid, ReportStatus::Resolved, collections).await;
+            }
+            StatusCodes::Success
+        }
+        Ok(_) => StatusCodes::LessonNotFound,
+        Err(_) => StatusCodes::GenericError,
+    }
+}
+
+pub async fn hide_lesson(
+    _admin: Admin,
+    Path(id): Path<String>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    Json(json!({"status": set_hidden(&id, true, collections).await}))
+}
+
+pub async fn unhide_lesson(
+    _admin: Admin,
+    Path(id): Path<String>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    Json(json!({"status": set_hidden(&id, false, collections).await}))
+}
+
+/// The images or audio in `ids` that no remaining lesson step uses. Remixes reuse
+/// their source's images, so an id may still be referenced elsewhere.
+async fn find_orphans(
+    ids: Vec<String>,
+    field: &str,
+    collections: &Collections
+) -> mongodb::error::Result<Vec<ObjectId>> {
+    let mut orphans = Vec::new();
+    for id in ids {
+        let Ok(oid) = ObjectId::parse_str(&id) else {
+            continue;
+        };
+        if collections.lessons.count_documents(doc! { format!("steps.{}", field): &id }).await? == 0 {
+            orphans.push(oid);
+        }
+    }
+    Ok(orphans)
+}
+
+/// Deletes a lesson with everything stored for it: its revisions, conversations,
+/// flashcards, review items, progress, classroom assignments and the link from the
+/// step it expands, plus any image or audio no other lesson uses. Reports on it are
+/// resolved and kept.
+pub async fn delete_lesson(
+    _admin: Admin,
+    Path(id): Path<String>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    jobs::cancel(&id);
+    let lesson = match collections.lessons.find_one_and_delete(doc! { "_id": oid }).await {
+        Ok(Some(lesson)) => lesson,
+        Ok(None) => {
+            return Json(json!({"status": StatusCodes::LessonNotFound}));
+        }
+        Err(_) => {
+            return Json(json!({"status": StatusCodes::GenericError}));
+        }
+    };
+    let steps = || lesson.steps.iter().flatten();
+    let images: Vec<String> = steps().filter_map(|step| step.image.clone()).collect();
+    let audio: Vec<String> = steps().filter_map(|step| step.tts.clone()).collect();
+
+    let related = doc! { "lesson": oid };
+    let cleanup = async {
+        collections.revisions.delete_many(related.clone()).await?;
+        collections.conversations.delete_many(related.clone()).await?;
+        collections.decks.delete_many(related.clone()).await?;
+        collections.reviews.delete_many(related.clone()).await?;
+        collections.progress.delete_many(related.clone()).await?;
+        collections.classrooms
+            .update_many(
+                doc! { "assignments.lesson": oid },
+                doc! { "$pull": { "assignments": related.clone() } }
+            ).await?;
+        if let Some(parent) = &lesson.expanded_from {
+            collections.lessons
+                .update_one(
+                    doc! { "_id": parent.lesson },
+                    doc! { "$pull": { format!("steps.{}.sub_lessons", parent.step): &id } }
+                ).await?;
+        }
+        let images = find_orphans(images, "image", collections).await?;
+        let audio = find_orphans(audio, "tts", collections).await?;
+        if !images.is_empty() {
+            collections.images.delete_many(doc! { "_id": { "$in": &images } }).await?;
+        }
+        if !audio.is_empty() {
+            collections.tts.delete_many(doc! { "_id": { "$in": &audio } }).await?;
+        }
+        Ok::<_, mongodb::error::Error>((images.len(), audio.len()))
+    };
+    let result = cleanup.await;
+    close_reports(oid, ReportStatus::Resolved, collections).await;
+    match result {
+        Ok((images, audio)) =>
+            Json(json!({"status": StatusCodes::Success, "deleted_images": images, "deleted_audio": audio})),
+        Err(e) => {
+            info!("Failed to clean up after deleting lesson {}: {}", id, e);
+            Json(json!({"status": StatusCodes::GenericError}))
+        }
+    }
+}
+
+pub async fn get_bans(_admin: Admin, collections: &Collections) -> impl IntoResponse + use<> {
+    let bans = async {
+        collections.bans.find(doc! {}).sort(doc! { "created_at": -1 }).await?.try_collect::<Vec<Ban>>().await
+    };
+    match bans.await {
+        Ok(bans) => Json(json!({"status": StatusCodes::Success, "bans": bans})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Bans a user id and/or address hash, or whoever requested `lesson`.
+pub async fn ban(
+    _admin: Admin,
+    extract::Json(body): extract::Json<NewBan>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let (user, ip_hash) = match &body.lesson {
+        Some(lesson) => {
+            let Ok(oid) = ObjectId::parse_str(lesson) else {
+                return Json(json!({"status": StatusCodes::InvalidID}));
+            };
+            match collections.lessons.find_one(doc! { "_id": oid }).await {
+                Ok(Some(lesson)) => (lesson.user, lesson.ip_hash),
+                Ok(None) => {
+                    return Json(json!({"status": StatusCodes::LessonNotFound}));
+                }
+                Err(_) => {
+                    return Json(json!({"status": StatusCodes::GenericError}));
+                }
+            }
+        }
+        None => {
+            let user = match body.user.as_deref() {
+                Some(user) => {
+                    let Some(user) = parse_user_id(user) else {
+                        return Json(json!({"status": StatusCodes::InvalidData}));
+                    };
+                    Some(user)
+                }
+                None => None,
+            };
+            (user, body.ip_hash.map(|h| h.trim().to_string()).filter(|h| !h.is_empty()))
+        }
+    };
+    if user.is_none() && ip_hash.is_none() {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let mut ban = Ban {
+        id: None,
+        user,
+        ip_hash,
+        reason: body.reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty()),
+        created_at: DateTime::now(),
+    };
+    match collections.bans.insert_one(&ban).await {
+        Ok(result) => {
+            ban.id = result.inserted_id.as_object_id();
+            Json(json!({"status": StatusCodes::Success, "ban": ban}))
+        }
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub async fn unban(_admin: Admin, Path(id): Path<String>, collections: &Collections) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    match collections.bans.delete_one(doc! { "_id": oid }).await {
+        Ok(result) if result.deleted_count == 1 => Json(json!({"status": StatusCodes::Success})),
+        Ok(_) => Json(json!({"status": StatusCodes::InvalidID})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new()
+        .route(
+            "/usage",
+            get({
+                let collections = Arc::clone(&collections);
+                move |admin, query| async move { get_usage(admin, query, &collections).await }
+            })
+        )
+        .route(
+            "/reports",
+            get({
+                let collections = Arc::clone(&collections);
+                move |admin, query| async move { get_reports(admin, query, &collections).await }
+            })
+        )
+        .route(
+            "/reports/{id}/dismiss",
+            post({
+                let collections = Arc::clone(&collections);
+                move |admin, params| async move { dismiss_report(admin, params, &collections).await }
+            })
+        )
+        .route(
+            "/lessons/{id}",
+            delete({
+                let collections = Arc::clone(&collections);
+                move |admin, params| async move { delete_lesson(admin, params, &collections).await }
+            })
+        )
+        .route(
+            "/lessons/{id}/hide",
+            post({
+                let collections = Arc::clone(&collections);
+                move |admin, params| async move { hide_lesson(admin, params, &collections).await }
+            })
+        )
+        .route(
+            "/lessons/{id}/unhide",
+            post({
+                let collections = Arc::clone(&collections);
+                move |admin, params| async move { unhide_lesson(admin, params, &collections).await }
+            })
+        )
+        .route(
+            "/bans",
+            get({
+                let collections = Arc::clone(&collections);
+                move |admin| async move { get_bans(admin, &collections).await }
+            }).post({
+                let collections = Arc::clone(&collections);
+                move |admin, body| async move { ban(admin, body, &collections).await }
+            })
+        )
+        .route(
+            "/bans/{id}",
+            delete({
+                let collections = Arc::clone(&collections);
+                move |admin, params| async move { unban(admin, params, &collections).await }
+            })
+        )
+}
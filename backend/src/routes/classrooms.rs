@@ -0,0 +1,535 @@
+use std::{ collections::HashMap, sync::Arc };
+
+use axum::{
+    extract::{ self, Path },
+    response::IntoResponse,
+    routing::{ delete, get, post },
+    Json,
+    Router,
+};
+use futures::TryStreamExt;
+use mongodb::bson::{ self, doc, oid::ObjectId, DateTime };
+use rand::Rng;
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+
+use crate::{
+    auth::{ Identity, IP_HASH_SALT },
+    types::{
+        Assignment,
+        Classroom,
+        JoinClassroom,
+        LessonStatus,
+        NewAssignment,
+        NewClassroom,
+        StatusCodes,
+        UserProgress,
+    },
+    utils::Collections,
+};
+
+/// Join codes leave out characters that are easy to mix up when read off a board.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 6;
+const MAX_NAME_CHARS: usize = 100;
+const MAX_STUDENTS: usize = 200;
+const MAX_ASSIGNMENTS: usize = 100;
+
+fn generate_code() -> String {
+    let mut rng = rand::rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.random_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Stands in for a student's user id towards the teacher. It is salted and tied to the
+/// classroom, so it cannot be used to act as the student or to follow them across
+/// classes.
+fn student_handle(classroom: ObjectId, student: &str) -> String {
+    let digest = Sha256::digest(format!("{}{}:{}", *IP_HASH_SALT, classroom.to_hex(), student));
+    format!("{:x}", digest)[..16].to_string()
+}
+
+/// Loads a classroom that `identity` teaches, or is enrolled in unless `teacher_only`.
+async fn load_classroom(
+    id: &str,
+    identity: &Identity,
+    teacher_only: bool,
+    collections: &Collections
+) -> Result<(ObjectId, Classroom), StatusCodes> {
+    let user = identity.user.as_deref().ok_or(StatusCodes::UserNotFound)?;
+    let oid = ObjectId::parse_str(id).map_err(|_| StatusCodes::InvalidID)?;
+    let classroom = collections.classrooms
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::InvalidID)?;
+    let is_student = !teacher_only && classroom.students.iter().any(|s| s == user);
+    if classroom.teacher != user && !is_student {
+        return Err(StatusCodes::Unauthorized);
+    }
+    Ok((oid, classroom))
+}
+
+/// What `user` gets to see of a classroom. Teachers see the roster as student
+/// handles rather than user ids; students do not see the roster, the join code or the
+/// teacher's user id, only their own handle so they can leave.
+fn view(classroom: &Classroom, user: &str) -> Value {
+    let handle = |student: &str| classroom.id.map(|id| student_handle(id, student));
+    if classroom.teacher == user {
+        let students: Vec<Option<String>> = classroom.students
+            .iter()
+            .map(|s| handle(s))
+            .collect();
+        return json!({
+            "_id": classroom.id,
+            "name": classroom.name,
+            "teacher": classroom.teacher,
+            "code": classroom.code,
+            "students": students,
+            "assignments": classroom.assignments,
+            "created_at": classroom.created_at,
+        });
+    }
+    json!({
+        "_id": classroom.id,
+        "name": classroom.name,
+        "assignments": classroom.assignments,
+        "created_at": classroom.created_at,
+        "handle": handle(user),
+    })
+}
+
+pub async fn create(
+    identity: Identity,
+    extract::Json(body): extract::Json<NewClassroom>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let mut code = generate_code();
+    // Codes are short, so make sure a new one is not already taken
+    for _ in 0..5 {
+        match collections.classrooms.count_documents(doc! { "code": &code }).await {
+            Ok(0) => break,
+            Ok(_) => {
+                code = generate_code();
+            }
+            Err(_) => {
+                return Json(json!({"status": StatusCodes::GenericError}));
+            }
+        }
+    }
+    let mut classroom = Classroom {
+        id: None,
+        name: name.to_string(),
+        teacher: user,
+        code,
+        students: Vec::new(),
+        assignments: Vec::new(),
+        created_at: DateTime::now(),
+    };
+    match collections.classrooms.insert_one(&classroom).await {
+        Ok(result) => {
+            classroom.id = result.inserted_id.as_object_id();
+            Json(json!({"status": StatusCodes::Success, "classroom": view(&classroom, &classroom.teacher)}))
+        }
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Classrooms the user teaches or is enrolled in.
+pub async fn list(identity: Identity, collections: &Collections) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    let classrooms: Result<Vec<Classroom>, _> = async {
+        collections.classrooms
+            .find(doc! { "$or": [{ "teacher": &user }, { "students": &user }] })
+            .sort(doc! { "created_at": -1 }).await?
+            .try_collect().await
+    }.await;
+    match classrooms {
+        Ok(classrooms) => {
+            let classrooms: Vec<Value> = classrooms
+                .iter()
+                .map(|c| view(c, &user))
+                .collect();
+            Json(json!({"status": StatusCodes::Success, "classrooms": classrooms}))
+        }
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub async fn get_classroom(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    match load_classroom(&id, &identity, false, collections).await {
+        Ok((_, classroom)) => {
+            let classroom = view(&classroom, identity.user.as_deref().unwrap_or_default());
+            Json(json!({"status": StatusCodes::Success, "classroom": classroom}))
+        }
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn delete_classroom(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let oid = match load_classroom(&id, &identity, true, collections).await {
+        Ok((oid, _)) => oid,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    match collections.classrooms.delete_one(doc! { "_id": oid }).await {
+        Ok(_) => Json(json!({"status": StatusCodes::Success})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub async fn join(
+    identity: Identity,
+    extract::Json(body): extract::Json<JoinClassroom>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    let code = body.code.trim().to_uppercase();
+    let classroom = match collections.classrooms.find_one(doc! { "code": &code }).await {
+        Ok(Some(classroom)) => classroom,
+        Ok(None) => {
+            return Json(json!({"status": StatusCodes::InvalidData}));
+        }
+        Err(_) => {
+            return Json(json!({"status": StatusCodes::GenericError}));
+        }
+    };
+    if classroom.teacher == user {
+        return Json(json!({"status": StatusCodes::InvalidState}));
+    }
+    if classroom.students.contains(&user) {
+        return Json(json!({"status": StatusCodes::Success, "classroom": view(&classroom, &user)}));
+    }
+    // The roster limit is checked in the filter so concurrent joins cannot exceed it
+    let result = collections.classrooms
+        .update_one(
+            doc! { "_id": classroom.id, format!("students.{}", MAX_STUDENTS - 1): { "$exists": false } },
+            doc! { "$addToSet": { "students": &user } }
+        ).await;
+    match result {
+        Ok(result) if result.matched_count == 0 => Json(json!({"status": StatusCodes::QuotaExceeded})),
+        Ok(_) => Json(json!({"status": StatusCodes::Success, "classroom": view(&classroom, &user)})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Removes the student with handle `student`. Teachers can remove any student;
+/// students can remove themselves to leave.
+pub async fn remove_student(
+    Path((id, student)): Path<(String, String)>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let (oid, classroom) = match load_classroom(&id, &identity, false, collections).await {
+        Ok(found) => found,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let Some(student) = classroom.students.iter().find(|s| student_handle(oid, s) == student) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let user = identity.user.as_deref().unwrap_or_default();
+    if classroom.teacher != user && student != user {
+        return Json(json!({"status": StatusCodes::Unauthorized}));
+    }
+    match collections.classrooms.update_one(doc! { "_id": oid }, doc! { "$pull": { "students": student } }).await {
+        Ok(_) => Json(json!({"status": StatusCodes::Success})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub async fn assign(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<NewAssignment>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let (oid, classroom) = match load_classroom(&id, &identity, true, collections).await {
+        Ok(found) => found,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let Ok(lesson) = ObjectId::parse_str(&body.lesson) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let due = match body.due.as_deref().map(DateTime::parse_rfc3339_str).transpose() {
+        Ok(due) => due,
+        Err(_) => {
+            return Json(json!({"status": StatusCodes::InvalidData}));
+        }
+    };
+    match collections.lessons.find_one(doc! { "_id": lesson }).await {
+        Ok(Some(record)) if record.status == LessonStatus::Completed => {}
+        Ok(Some(_)) => {
+            return Json(json!({"status": StatusCodes::InvalidState}));
+        }
+        Ok(None) => {
+            return Json(json!({"status": StatusCodes::LessonNotFound}));
+        }
+        Err(_) => {
+            return Json(json!({"status": StatusCodes::GenericError}));
+        }
+    }
+    let assignment = Assignment { lesson, due, assigned_at: DateTime::now() };
+    // Assigning a lesson again only moves its due date
+    let existing = classroom.assignments.iter().any(|a| a.lesson == lesson);
+    let result = if existing {
+        collections.classrooms.update_one(
+            doc! { "_id": oid, "assignments.lesson": lesson },
+            doc! { "$set": { "assignments.$.due": due } }
+        ).await
+    } else if classroom.assignments.len() >= MAX_ASSIGNMENTS {
+        return Json(json!({"status": StatusCodes::QuotaExceeded}));
+    } else {
+        collections.classrooms.update_one(
+            doc! { "_id": oid },
+            doc! { "$push": { "assignments": bson::to_bson(&assignment).unwrap() } }
+        ).await
+    };
+    match result {
+        Ok(_) => Json(json!({"status": StatusCodes::Success, "assignment": assignment})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub async fn unassign(
+    Path((id, lesson)): Path<(String, String)>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let oid = match load_classroom(&id, &identity, true, collections).await {
+        Ok((oid, _)) => oid,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let Ok(lesson) = ObjectId::parse_str(&lesson) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let result = collections.classrooms.update_one(
+        doc! { "_id": oid },
+        doc! { "$pull": { "assignments": { "lesson": lesson } } }
+    ).await;
+    match result {
+        Ok(_) => Json(json!({"status": StatusCodes::Success})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Fraction of quiz questions answered correctly, or `None` without quizzes.
+fn quiz_score<'a>(progress: impl Iterator<Item = &'a UserProgress>) -> Option<f64> {
+    let (score, total) = progress
+        .flat_map(|p| &p.quizzes)
+        .fold((0u64, 0u64), |(score, total), quiz| (
+            score + u64::from(quiz.score),
+            total + u64::from(quiz.total),
+        ));
+    (total > 0).then(|| (score as f64) / (total as f64))
+}
+
+/// Each student's progress and quiz scores on every assigned lesson, for the teacher.
+pub async fn dashboard(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let (oid, classroom) = match load_classroom(&id, &identity, true, collections).await {
+        Ok(found) => found,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let lessons: Vec<ObjectId> = classroom.assignments
+        .iter()
+        .map(|a| a.lesson)
+        .collect();
+    let loaded = futures::try_join!(
+        async {
+            collections.lessons
+                .find(doc! { "_id": { "$in": &lessons } })
+                .projection(doc! { "title": 1, "outline": 1, "prompt": 1, "difficulty": 1 }).await?
+                .try_collect::<Vec<_>>().await
+        },
+        async {
+            collections.progress
+                .find(doc! { "user": { "$in": &classroom.students }, "lesson": { "$in": &lessons } }).await?
+                .try_collect::<Vec<_>>().await
+        }
+    );
+    let Ok((records, progress)) = loaded else {
+        return Json(json!({"status": StatusCodes::GenericError}));
+    };
+    let titles: HashMap<ObjectId, (String, usize)> = records
+        .into_iter()
+        .filter_map(|l| l.id.map(|id| (id, (l.title, l.outline.len()))))
+        .collect();
+    let progress: HashMap<(&str, ObjectId), &UserProgress> = progress
+        .iter()
+        .map(|p| ((p.user.as_str(), p.lesson), p))
+        .collect();
+
+    let assignments: Vec<Value> = classroom.assignments
+        .iter()
+        .map(|a| {
+            let (title, steps) = titles.get(&a.lesson).cloned().unwrap_or_default();
+            let completed = classroom.students
+                .iter()
+                .filter(|s| progress.get(&(s.as_str(), a.lesson)).is_some_and(|p| p.completed))
+                .count();
+            json!({
+                "lesson": a.lesson,
+                "title": title,
+                "steps": steps,
+                "due": a.due,
+                "completed": completed,
+                "quiz_score": quiz_score(
+                    classroom.students.iter().filter_map(|s| progress.get(&(s.as_str(), a.lesson)).copied())
+                ),
+            })
+        })
+        .collect();
+    let students: Vec<Value> = classroom.students
+        .iter()
+        .map(|student| {
+            let mine: Vec<&UserProgress> = lessons
+                .iter()
+                .filter_map(|lesson| progress.get(&(student.as_str(), *lesson)).copied())
+                .collect();
+            let lessons: Vec<Value> = classroom.assignments
+                .iter()
+                .map(|a| {
+                    let steps = titles.get(&a.lesson).map(|(_, steps)| *steps).unwrap_or_default();
+                    match progress.get(&(student.as_str(), a.lesson)) {
+                        Some(p) => {
+                            let late = match (a.due, p.completed_at) {
+                                (Some(due), Some(done)) => done > due,
+                                (Some(due), None) => DateTime::now() > due,
+                                (None, _) => false,
+                            };
+                            json!({
+                                "lesson": a.lesson,
+                                "started": true,
+                                "viewed": p.viewed.len(),
+                                "steps": steps,
+                                "listened": p.listened.len(),
+                                "time_spent": p.time_spent,
+                                "completed": p.completed,
+                                "completed_at": p.completed_at,
+                                "late": late,
+                                "quizzes": p.quizzes.len(),
+                                "quiz_score": quiz_score(std::iter::once(*p)),
+                                "updated_at": p.updated_at,
+                            })
+                        }
+                        None =>
+                            json!({
+                            "lesson": a.lesson,
+                            "started": false,
+                            "steps": steps,
+                            "completed": false,
+                            "late": a.due.is_some_and(|due| DateTime::now() > due),
+                        }),
+                    }
+                })
+                .collect();
+            json!({
+                "student": student_handle(oid, student),
+                "completed": mine.iter().filter(|p| p.completed).count(),
+                "time_spent": mine.iter().map(|p| p.time_spent).sum::<u64>(),
+                "quiz_score": quiz_score(mine.into_iter()),
+                "lessons": lessons,
+            })
+        })
+        .collect();
+    Json(
+        json!({
+        "status": StatusCodes::Success,
+        "classroom": { "_id": classroom.id, "name": classroom.name },
+        "assignments": assignments,
+        "students": students,
+    })
+    )
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get({
+                let collections = Arc::clone(&collections);
+                move |identity| async move { list(identity, &collections).await }
+            }).post({
+                let collections = Arc::clone(&collections);
+                move |identity, body| async move { create(identity, body, &collections).await }
+            })
+        )
+        .route(
+            "/join",
+            post({
+                let collections = Arc::clone(&collections);
+                move |identity, body| async move { join(identity, body, &collections).await }
+            })
+        )
+        .route(
+            "/{id}",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { get_classroom(params, identity, &collections).await }
+            }).delete({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move {
+                    delete_classroom(params, identity, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/students/{student}",
+            delete({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { remove_student(params, identity, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/assignments",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move { assign(params, identity, body, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/assignments/{lesson}",
+            delete({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { unassign(params, identity, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/dashboard",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { dashboard(params, identity, &collections).await }
+            })
+        )
+}
@@ -0,0 +1,103 @@
+use std::sync::{ Arc, LazyLock };
+
+use axum::{ extract::Path, response::{ IntoResponse, Response }, routing::get, Json, Router };
+use serde_json::json;
+use tracing::info;
+
+use crate::{
+    auth::Identity,
+    flashcards,
+    ratelimit::{ Limit, RateLimitLayer },
+    types::StatusCodes,
+    utils::Collections,
+};
+
+/// Shared by the deck and its exports, since the first of them to be requested
+/// generates the deck.
+static FLASHCARD_LIMIT: LazyLock<RateLimitLayer> = LazyLock::new(||
+    RateLimitLayer::new(Limit::from_env("FLASHCARDS", 20))
+);
+
+pub async fn get_flashcards(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    match flashcards::get_deck(&id, identity.user.as_deref(), collections).await {
+        Ok((_, deck)) => Json(json!({"status": StatusCodes::Success, "flashcards": deck.cards})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn export_csv(Path(id): Path<String>, identity: Identity, collections: &Collections) -> Response {
+    let deck = match flashcards::get_deck(&id, identity.user.as_deref(), collections).await {
+        Ok((_, deck)) => deck,
+        Err(status) => {
+            return Json(json!({"status": status})).into_response();
+        }
+    };
+    let data = flashcards::to_csv(&deck);
+    axum::response::Response
+        ::builder()
+        .header("Content-Type", "text/csv; charset=utf-8")
+        .header("Content-Disposition", format!("attachment; filename=\"canvas-{}.csv\"", id))
+        .header("Content-Length", data.len())
+        .body(axum::body::Body::from(data))
+        .unwrap()
+}
+
+pub async fn export_apkg(Path(id): Path<String>, identity: Identity, collections: &Collections) -> Response {
+    let (lesson, deck) = match flashcards::get_deck(&id, identity.user.as_deref(), collections).await {
+        Ok(found) => found,
+        Err(status) => {
+            return Json(json!({"status": status})).into_response();
+        }
+    };
+    let images = flashcards::load_images(&deck, collections).await;
+    let package = tokio::task::spawn_blocking(move || flashcards::to_apkg(&deck, &lesson.title, &images)).await;
+    let data = match package {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => {
+            info!("Failed to export lesson {} to Anki: {}", id, e);
+            return Json(json!({"status": StatusCodes::GenericError})).into_response();
+        }
+        Err(e) => {
+            info!("Anki export task for lesson {} failed: {}", id, e);
+            return Json(json!({"status": StatusCodes::GenericError})).into_response();
+        }
+    };
+    axum::response::Response
+        ::builder()
+        .header("Content-Type", "application/octet-stream")
+        .header("Content-Disposition", format!("attachment; filename=\"canvas-{}.apkg\"", id))
+        .header("Content-Length", data.len())
+        .body(axum::body::Body::from(data))
+        .unwrap()
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new()
+        .route(
+            "/{id}/flashcards",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move {
+                    get_flashcards(params, identity, &collections).await
+                }
+            }).layer(FLASHCARD_LIMIT.clone())
+        )
+        .route(
+            "/{id}/flashcards/csv",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { export_csv(params, identity, &collections).await }
+            }).layer(FLASHCARD_LIMIT.clone())
+        )
+        .route(
+            "/{id}/flashcards/apkg",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { export_apkg(params, identity, &collections).await }
+            }).layer(FLASHCARD_LIMIT.clone())
+        )
+}
@@ -1,7 +1,6 @@
 use std::sync::Arc;
 
 use axum::{
-    body::Body,
     extract::Path,
     response::{ IntoResponse, Response },
     routing::get,
@@ -13,10 +12,10 @@ use mongodb::bson::{ doc, oid::ObjectId };
 use serde_json::json;
 use tracing::info;
 // use tracing::info;
-use crate::{ types::StatusCodes, utils::Collections };
+use crate::{ ratelimit::{ Limit, RateLimitLayer }, types::StatusCodes, utils::Collections };
 
 pub async fn get_image(Path(id): Path<String>, collections: &Collections) -> Response {
-    if id.len() == 0 {
+    if id.is_empty() {
         return Json(json!({"status": StatusCodes::InvalidID})).into_response();
     }
     let image = collections.images
@@ -48,11 +47,11 @@ pub async fn get_image(Path(id): Path<String>, collections: &Collections) -> Res
                     .unwrap();
 
                 // Now `response` is ready to be returned
-                return response;
+                response
             } else {
                 // Handle invalid data URI case
                 // e.g., return an error response
-                return Json(json!({"status": StatusCodes::GenericError})).into_response();
+                Json(json!({"status": StatusCodes::GenericError})).into_response()
             }
         }
         None => Json(json!({"status": StatusCodes::GenericError})).into_response(),
@@ -69,7 +68,7 @@ pub fn get_routes(collections: Arc<Collections>) -> Router {
             "/{id}",
             get({
                 let collections = Arc::clone(&collections);
-                move |params| async move { get_image(params, &*collections).await }
-            })
+                move |params| async move { get_image(params, &collections).await }
+            }).layer(RateLimitLayer::new(Limit::from_env("IMAGES", 120)))
         )
 }
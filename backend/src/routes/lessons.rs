@@ -3,52 +3,447 @@ use std::sync::Arc;
 use axum::{
     extract::{ self, Path },
     response::IntoResponse,
-    routing::{ get, post },
+    routing::{ get, patch, post, put },
     Json,
     Router,
 };
-use mongodb::bson::{doc, oid::ObjectId, Array};
-use serde_json::json;
-use tokio::task;
-use crate::{ types::{ Difficulty, Lesson, StatusCodes }, utils::{start_lesson_pipeline, Collections} };
+use mongodb::{ bson::{ self, doc, oid::ObjectId }, options::ReturnDocument };
+use serde_json::{ json, Value };
+use tracing::info;
+use crate::{
+    auth::Identity,
+    jobs,
+    moderation::{ Subject, MODERATION },
+    quota,
+    ratelimit::{ Limit, RateLimitLayer },
+    routes::museum,
+    types::{
+        Audience,
+        Lesson,
+        LessonMode,
+        LessonRecord,
+        LessonStatus,
+        ModerationVerdict,
+        NewOutlineItem,
+        OutlineItem,
+        OutlineItemPatch,
+        OutlineOrder,
+        Public,
+        Remix,
+        StatusCodes,
+        StepRef,
+    },
+    utils::{ self, spawn_lesson_pipeline, Collections, PipelineRun },
+};
 
 pub async fn start(
+    identity: Identity,
     extract::Json(body): extract::Json<Lesson>,
     collections: &Collections
 ) -> impl IntoResponse + use<> {
-    let report: Lesson = body.into();
-    if report.prompt.len() == 0 {
+    let report: Lesson = body;
+    if report.prompt.is_empty() {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let Ok(audience) = check_audience(report.audience) else {
         return Json(json!({"status": StatusCodes::InvalidData}));
-    } else if report.difficulty != Difficulty::Elementary && report.difficulty != Difficulty::HighSchool && report.difficulty != Difficulty::University {
+    };
+    let Some(difficulty) = report.difficulty.or_else(|| audience.as_ref().and_then(Audience::difficulty)) else {
         return Json(json!({"status": StatusCodes::InvalidData}));
+    };
+    if let Err(status) = quota::check_lesson_rate(&identity, collections).await {
+        return Json(json!({"status": status}));
     }
-    let result = collections.lessons.insert_one(doc! {
-        "prompt": report.prompt.clone(),
-        "difficulty": report.difficulty.clone() as i32,
-        "title": "",
-        "description": "",
-        "outline": Array::new(),
-        "steps": Array::new(),
-    }).await;
+    let moderation = match screen_request(Some(&report.prompt), audience.as_ref(), &identity, collections).await {
+        Ok(moderation) => moderation,
+        Err(response) => {
+            return response;
+        }
+    };
+    let mut lesson = LessonRecord::new(report.prompt, difficulty, identity.user.clone()).with_audience(audience);
+    lesson.ip_hash = identity.ip_hash();
+    lesson.hidden = moderation.is_some();
+    lesson.moderation = moderation;
+    spawn_lesson(lesson, report.mode, collections).await
+}
+
+/// Screens what a user wrote for a new lesson. Blocked text rejects the request with
+/// the reason; flagged text comes back to be stored on the lesson.
+async fn screen_request(
+    text: Option<&str>,
+    audience: Option<&Audience>,
+    identity: &Identity,
+    collections: &Collections
+) -> Result<Option<ModerationVerdict>, Json<Value>> {
+    let notes = audience.iter().flat_map(|a| a.notes.iter().map(String::as_str));
+    let text = text.into_iter().chain(notes).collect::<Vec<_>>().join("\n\n");
+    let ip_hash = identity.ip_hash();
+    let subject = Subject { lesson: None, user: identity.user.as_deref(), ip_hash: ip_hash.as_deref() };
+    MODERATION.screen_request(subject, "prompt", None, &text, collections).await.map_err(
+        |verdict| Json(json!({"status": StatusCodes::Rejected, "reason": verdict.reason}))
+    )
+}
+
+/// Drops an audience with nothing in it and rejects one with out of range values.
+fn check_audience(audience: Option<Audience>) -> Result<Option<Audience>, StatusCodes> {
+    match audience.filter(|a| *a != Audience::default()) {
+        Some(audience) if !audience.is_valid() => Err(StatusCodes::InvalidData),
+        audience => Ok(audience),
+    }
+}
+
+/// Stores a new lesson and starts generating it in the background. Review mode needs
+/// a user, since only the owner can edit and approve the outline.
+async fn spawn_lesson(mut lesson: LessonRecord, mode: LessonMode, collections: &Collections) -> Json<Value> {
+    if mode == LessonMode::Review && lesson.user.is_none() {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    }
+    let result = collections.lessons.insert_one(&lesson).await;
     if result.is_err() {
         return Json(json!({"status": StatusCodes::GenericError}));
     }
-    let id = result.as_ref().unwrap().inserted_id.as_object_id().unwrap().to_string();
-    task::spawn(start_lesson_pipeline(report.prompt, report.difficulty, id.clone(), collections.clone()));
+    lesson.id = result.as_ref().unwrap().inserted_id.as_object_id();
+    let id = lesson.id.unwrap().to_string();
+    let run = match mode {
+        LessonMode::Full => PipelineRun::Full,
+        LessonMode::Review => PipelineRun::OutlineOnly,
+    };
+    spawn_lesson_pipeline(lesson, collections.clone(), run);
 
-    return Json(json!({"status": StatusCodes::Success, "id": id}));
+    Json(json!({"status": StatusCodes::Success, "id": id}))
 }
-pub async fn get_lesson(Path(id): Path<String>, collections: &Collections) -> impl IntoResponse + use<> {
-    if id.len() == 0 {
+
+/// Starts a sub-lesson that goes deeper into one step of lesson `id`, at the same
+/// difficulty and for the same audience. The child is linked from the step's
+/// `sub_lessons` and points back to the step through `expanded_from`. Hidden and
+/// blocked lessons cannot be expanded.
+pub async fn expand(
+    Path((id, index)): Path<(String, usize)>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let parent = match museum::find_listed(oid, collections).await {
+        Ok(parent) => parent,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let Some(Some(step)) = parent.steps.get(index) else {
+        return Json(json!({"status": StatusCodes::InvalidNumber}));
+    };
+    if let Err(status) = quota::check_lesson_rate(&identity, collections).await {
+        return Json(json!({"status": status}));
+    }
+    let mut lesson = LessonRecord::new(step.title.clone(), parent.difficulty, identity.user.clone()).with_audience(
+        parent.audience.clone()
+    );
+    lesson.ip_hash = identity.ip_hash();
+    lesson.expanded_from = Some(StepRef { lesson: oid, step: index as u32 });
+    let response = spawn_lesson(lesson, LessonMode::Full, collections).await;
+    if let Some(child) = response["id"].as_str() {
+        collections.lessons
+            .update_one(
+                doc! { "_id": oid },
+                doc! { "$push": { format!("steps.{}.sub_lessons", index): child } }
+            ).await
+            .ok();
+    }
+    response
+}
+
+/// Starts a new lesson on the same topic as lesson `id`, adapted to another difficulty
+/// or following extra instructions. The source outline guides the new one and its
+/// images are reused where steps match. Only completed lessons the museum shows can
+/// be remixed.
+pub async fn remix(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<Remix>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let instructions = body.instructions
+        .map(|i| i.trim().to_string())
+        .filter(|i| !i.is_empty());
+    let Ok(audience) = check_audience(body.audience) else {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    };
+    if body.difficulty.is_none() && audience.is_none() && instructions.is_none() {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let source = match museum::find_listed(oid, collections).await {
+        Ok(source) => source,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    if source.status != LessonStatus::Completed || source.outline.is_empty() {
+        return Json(json!({"status": StatusCodes::InvalidState}));
+    }
+    if let Err(status) = quota::check_lesson_rate(&identity, collections).await {
+        return Json(json!({"status": status}));
+    }
+    // A new difficulty without an audience replaces the source audience too
+    let audience = match (&audience, &body.difficulty) {
+        (None, None) => source.audience.clone(),
+        _ => audience,
+    };
+    let difficulty = body.difficulty
+        .or_else(|| audience.as_ref().and_then(Audience::difficulty))
+        .unwrap_or(source.difficulty);
+    // The source prompt is screened again, since it may predate moderation
+    let request = [Some(source.prompt.as_str()), instructions.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let moderation = match screen_request(Some(&request), audience.as_ref(), &identity, collections).await {
+        Ok(moderation) => moderation,
+        Err(response) => {
+            return response;
+        }
+    };
+    let mut lesson = LessonRecord::new(source.prompt, difficulty, identity.user.clone()).with_audience(audience);
+    lesson.ip_hash = identity.ip_hash();
+    lesson.hidden = moderation.is_some();
+    lesson.moderation = moderation;
+    lesson.remixed_from = Some(oid);
+    lesson.remix_instructions = instructions;
+    spawn_lesson(lesson, body.mode, collections).await
+}
+
+/// Applies `edit` to the outline of a lesson that is waiting in review and stores the
+/// result. Only the user who requested the lesson may edit it.
+async fn edit_outline(
+    id: &str,
+    identity: &Identity,
+    collections: &Collections,
+    edit: impl FnOnce(&mut Vec<OutlineItem>) -> Result<(), StatusCodes>
+) -> Json<Value> {
+    let Ok(oid) = ObjectId::parse_str(id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let lesson = match collections.lessons.find_one(doc! { "_id": oid }).await {
+        Ok(Some(lesson)) => lesson,
+        Ok(None) => {
+            return Json(json!({"status": StatusCodes::LessonNotFound}));
+        }
+        Err(_) => {
+            return Json(json!({"status": StatusCodes::GenericError}));
+        }
+    };
+    if !lesson.is_owned_by(identity.user.as_deref()) {
+        return Json(json!({"status": StatusCodes::Unauthorized}));
+    }
+    if lesson.status != LessonStatus::Review {
+        return Json(json!({"status": StatusCodes::InvalidState}));
+    }
+    let mut outline = lesson.outline.clone();
+    if let Err(status) = edit(&mut outline) {
+        return Json(json!({"status": status}));
+    }
+    if outline.is_empty() || outline.iter().any(|item| item.title.trim().is_empty() || item.prompt.trim().is_empty()) {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    let max_steps = quota::QUOTAS.max_steps_per_lesson;
+    if max_steps > 0 && outline.len() > max_steps {
+        return Json(json!({"status": StatusCodes::QuotaExceeded}));
+    }
+    // Items kept from the generated outline were screened with it
+    let written: Vec<String> = outline
+        .iter()
+        .filter(|item| !lesson.outline.contains(item))
+        .map(|item| [item.title.as_str(), item.prompt.as_str(), item.speech.as_str()].join("\n"))
+        .collect();
+    let ip_hash = identity.ip_hash();
+    let subject = Subject { lesson: Some(oid), user: identity.user.as_deref(), ip_hash: ip_hash.as_deref() };
+    match MODERATION.screen_request(subject, "outline", None, &written.join("\n"), collections).await {
+        Ok(None) => {}
+        Ok(Some(verdict)) => {
+            if let Err(e) = utils::store_verdict(oid, &verdict, collections).await {
+                info!("{}", e);
+            }
+        }
+        Err(verdict) => {
+            return Json(json!({"status": StatusCodes::Rejected, "reason": verdict.reason}));
+        }
+    }
+    // Only store the edit if generation has not been started in the meantime
+    let result = collections.lessons
+        .update_one(
+            doc! { "_id": oid, "status": bson::to_bson(&LessonStatus::Review).unwrap() },
+            doc! { "$set": { "outline": bson::to_bson(&outline).unwrap() } }
+        ).await;
+    match result {
+        Ok(result) if result.matched_count == 1 => Json(json!({"status": StatusCodes::Success, "outline": outline})),
+        Ok(_) => Json(json!({"status": StatusCodes::InvalidState})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub async fn add_outline_item(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<NewOutlineItem>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    edit_outline(&id, &identity, collections, |outline| {
+        let index = body.index.unwrap_or(outline.len());
+        if index > outline.len() {
+            return Err(StatusCodes::InvalidNumber);
+        }
+        outline.insert(index, body.item);
+        Ok(())
+    }).await
+}
+
+pub async fn update_outline_item(
+    Path((id, index)): Path<(String, usize)>,
+    identity: Identity,
+    extract::Json(body): extract::Json<OutlineItemPatch>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    edit_outline(&id, &identity, collections, |outline| {
+        let item = outline.get_mut(index).ok_or(StatusCodes::InvalidNumber)?;
+        if let Some(title) = body.title {
+            item.title = title;
+        }
+        if let Some(media_type) = body.media_type {
+            item.media_type = media_type;
+        }
+        if let Some(prompt) = body.prompt {
+            item.prompt = prompt;
+        }
+        if let Some(speech) = body.speech {
+            item.speech = speech;
+        }
+        Ok(())
+    }).await
+}
+
+pub async fn remove_outline_item(
+    Path((id, index)): Path<(String, usize)>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    edit_outline(&id, &identity, collections, |outline| {
+        if index >= outline.len() {
+            return Err(StatusCodes::InvalidNumber);
+        }
+        outline.remove(index);
+        Ok(())
+    }).await
+}
+
+pub async fn reorder_outline(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<OutlineOrder>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    edit_outline(&id, &identity, collections, |outline| {
+        // `order` must be a permutation of the current indices
+        let mut sorted = body.order.clone();
+        sorted.sort_unstable();
+        if !sorted.iter().copied().eq(0..outline.len()) {
+            return Err(StatusCodes::InvalidData);
+        }
+        *outline = body.order.iter().map(|&i| outline[i].clone()).collect();
+        Ok(())
+    }).await
+}
+
+/// Starts step generation for a lesson whose outline is in review.
+pub async fn generate(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
         return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    // Only the owner can approve the outline
+    let filter = doc! { "_id": oid, "status": bson::to_bson(&LessonStatus::Review).unwrap(), "user": user };
+    // Claiming the lesson by switching its status makes sure it is only generated once
+    let claimed = collections.lessons
+        .find_one_and_update(
+            filter,
+            doc! { "$set": { "status": bson::to_bson(&LessonStatus::Generating).unwrap() } }
+        )
+        .return_document(ReturnDocument::After).await;
+    match claimed {
+        Ok(Some(lesson)) => {
+            spawn_lesson_pipeline(lesson, collections.clone(), PipelineRun::StepsOnly);
+            Json(json!({"status": StatusCodes::Success, "id": id}))
+        }
+        Ok(None) =>
+            match collections.lessons.find_one(doc! { "_id": oid }).await {
+                Ok(Some(lesson)) if lesson.status == LessonStatus::Review => Json(json!({"status": StatusCodes::Unauthorized})),
+                Ok(Some(_)) => Json(json!({"status": StatusCodes::InvalidState})),
+                Ok(None) => Json(json!({"status": StatusCodes::LessonNotFound})),
+                Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+            }
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
     }
-    let lesson = collections.lessons.find_one(doc! { "_id":  ObjectId::parse_str(id).unwrap() }).await.unwrap_or(None);
+}
+pub async fn get_lesson(Path(id): Path<String>, collections: &Collections) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let lesson = collections.lessons.find_one(doc! { "_id": oid }).await.unwrap_or(None);
     match lesson.clone() {
-        Some(lesson) => Json(json!({"status": StatusCodes::Success, "lesson": lesson})),
+        Some(lesson) => Json(json!({"status": StatusCodes::Success, "lesson": Public(&lesson)})),
         None => Json(json!({"status": StatusCodes::LessonNotFound})),
     }
 }
 
+pub async fn get_glossary(Path(id): Path<String>, collections: &Collections) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    match collections.lessons.find_one(doc! { "_id": oid }).await {
+        Ok(Some(lesson)) => Json(json!({"status": StatusCodes::Success, "glossary": lesson.glossary})),
+        Ok(None) => Json(json!({"status": StatusCodes::LessonNotFound})),
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+/// Stops the pipeline generating lesson `id`, keeping the steps stored so far. Only
+/// the user who requested the lesson may cancel it, so lessons requested without a
+/// user id run to the end. Shared by the REST endpoint and the `cancel_lesson` socket
+/// event.
+pub async fn cancel_lesson(id: &str, identity: &Identity, collections: &Collections) -> StatusCodes {
+    let Ok(oid) = ObjectId::parse_str(id) else {
+        return StatusCodes::InvalidID;
+    };
+    if identity.user.is_none() {
+        return StatusCodes::UserNotFound;
+    }
+    match collections.lessons.find_one(doc! { "_id": oid }).await {
+        Ok(Some(lesson)) if !lesson.is_owned_by(identity.user.as_deref()) => StatusCodes::Unauthorized,
+        Ok(Some(_)) if jobs::cancel(id) => StatusCodes::Success,
+        Ok(Some(_)) => StatusCodes::NotGenerating,
+        Ok(None) => StatusCodes::LessonNotFound,
+        Err(_) => StatusCodes::GenericError,
+    }
+}
+
+pub async fn cancel(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    Json(json!({"status": cancel_lesson(&id, &identity, collections).await}))
+}
+
 pub fn get_routes(collections: Arc<Collections>) -> Router {
     Router::new()
         .route(
@@ -59,14 +454,83 @@ pub fn get_routes(collections: Arc<Collections>) -> Router {
             "/start",
             post({
                 let collections = Arc::clone(&collections);
-                move |body| async move { start(body, &*collections).await }
+                move |identity, body| async move { start(identity, body, &collections).await }
             })
         )
         .route(
             "/{id}",
             get({
                 let collections = Arc::clone(&collections);
-                move |params| async move { get_lesson(params, &*collections).await }
+                move |params| async move { get_lesson(params, &collections).await }
+            }).layer(RateLimitLayer::new(Limit::from_env("LESSONS", 60)))
+        )
+        .route(
+            "/{id}/glossary",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params| async move { get_glossary(params, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/cancel",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { cancel(params, identity, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/outline",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move {
+                    add_outline_item(params, identity, body, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/outline/order",
+            put({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move {
+                    reorder_outline(params, identity, body, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/outline/{index}",
+            patch({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move {
+                    update_outline_item(params, identity, body, &collections).await
+                }
+            }).delete({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move {
+                    remove_outline_item(params, identity, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/remix",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move {
+                    remix(params, identity, body, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/steps/{index}/expand",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { expand(params, identity, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/generate",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { generate(params, identity, &collections).await }
             })
         )
 }
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+
+use axum::{ response::IntoResponse, routing::get, Json, Router };
+use serde_json::json;
+
+use crate::{ auth::Identity, progress, types::StatusCodes, utils::Collections };
+
+pub async fn get_progress(identity: Identity, collections: &Collections) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    match progress::list_progress(&user, collections).await {
+        Ok(progress) => Json(json!({"status": StatusCodes::Success, "progress": progress})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new().route(
+        "/progress",
+        get({
+            let collections = Arc::clone(&collections);
+            move |identity| async move { get_progress(identity, &collections).await }
+        })
+    )
+}
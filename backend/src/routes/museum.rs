@@ -1,31 +1,117 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::Path,
+    extract::{ Path, Query },
     response::IntoResponse,
     routing::get,
     Json,
     Router,
 };
 use futures::StreamExt;
-use mongodb::bson::doc;
+use mongodb::bson::{ doc, oid::ObjectId, Document };
+use serde::Deserialize;
 use serde_json::json;
 
-use crate::{types::StatusCodes, utils::Collections};
+use crate::{
+    ratelimit::{ Limit, RateLimitLayer },
+    types::{ LessonRecord, Public, StatusCodes },
+    utils::Collections,
+};
+
+/// Optional gallery filters. Durations are in minutes; lessons stored before
+/// durations were estimated only match when no duration filter is given.
+#[derive(Debug, Deserialize, Default)]
+pub struct GalleryQuery {
+    pub min_minutes: Option<u32>,
+    pub max_minutes: Option<u32>,
+    /// Text that one of the lesson's objectives contains.
+    pub objective: Option<String>,
+    /// Text that one of the lesson's prerequisites contains.
+    pub prerequisite: Option<String>,
+    /// Only lessons that need no prior knowledge.
+    #[serde(default)]
+    pub no_prerequisites: bool,
+}
+
+/// Case-insensitive "contains" match on array elements, with `text` taken literally.
+fn contains(text: &str) -> Document {
+    let escaped: String = text
+        .trim()
+        .chars()
+        .flat_map(|c| {
+            let special = "\\.+*?()|[]{}^$".contains(c);
+            special.then_some('\\').into_iter().chain(std::iter::once(c))
+        })
+        .collect();
+    doc! { "$regex": escaped, "$options": "i" }
+}
+
+/// Lessons the museum shows. Flagged lessons are hidden until a moderator unhides
+/// them; blocked ones never show. Lessons flagged before `hidden` was stored have no
+/// such field and stay hidden too.
+fn listed_filter() -> Document {
+    doc! {
+        "hidden": { "$ne": true },
+        "moderation.action": { "$ne": "block" },
+        "$or": [{ "moderation.action": { "$ne": "flag" } }, { "hidden": false }],
+    }
+}
 
-pub async fn get_gallery(Path(count): Path<String>, collections: &Collections) -> impl IntoResponse + use<> {
+/// Loads lesson `oid` for building on it, as long as the museum would show it.
+/// Hidden and blocked lessons are reported as not found.
+pub async fn find_listed(oid: ObjectId, collections: &Collections) -> Result<LessonRecord, StatusCodes> {
+    let mut filter = listed_filter();
+    filter.insert("_id", oid);
+    collections.lessons
+        .find_one(filter).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::LessonNotFound)
+}
+
+fn gallery_filter(query: &GalleryQuery) -> Document {
+    let mut filter = listed_filter();
+    if query.min_minutes.is_some() || query.max_minutes.is_some() {
+        let mut duration = doc! { "$gt": 0 };
+        if let Some(min) = query.min_minutes {
+            duration.insert("$gte", i64::from(min) * 60);
+        }
+        if let Some(max) = query.max_minutes {
+            duration.insert("$lte", i64::from(max) * 60);
+        }
+        filter.insert("duration", duration);
+    }
+    if let Some(objective) = query.objective.as_deref().filter(|o| !o.trim().is_empty()) {
+        filter.insert("objectives", contains(objective));
+    }
+    if query.no_prerequisites {
+        filter.insert("prerequisites", doc! { "$size": 0 });
+    } else if let Some(prerequisite) = query.prerequisite.as_deref().filter(|p| !p.trim().is_empty()) {
+        filter.insert("prerequisites", contains(prerequisite));
+    }
+    filter
+}
+
+pub async fn get_gallery(
+    Path(count): Path<String>,
+    Query(query): Query<GalleryQuery>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
     let count = count.parse::<i64>().unwrap_or(0);
     if count <= 0 {
         return Json(json!({"status": StatusCodes::InvalidData}));
     }
+    if let (Some(min), Some(max)) = (query.min_minutes, query.max_minutes) && min > max {
+        return Json(json!({"status": StatusCodes::InvalidNumber}));
+    }
     // get n gallery items from the database whre n = count, us the limit
     let gallery = collections.lessons
-        .find(doc! {}).limit(count).await.unwrap();
+        .find(gallery_filter(&query)).limit(count).await.unwrap();
    let gallery = gallery
         .collect::<Vec<_>>().await
         .into_iter()
         .map(|s| s.unwrap())
         .collect::<Vec<_>>();
+    let gallery: Vec<Public<_>> = gallery.iter().map(Public).collect();
     Json(json!({"status": StatusCodes::Success, "gallery": gallery}))
 }
 
@@ -35,7 +121,7 @@ pub fn get_routes(collections: Arc<Collections>) -> Router {
             "/gallery/{count}",
             get({
                 let collections = Arc::clone(&collections);
-                move |params| async move { get_gallery(params, &*collections).await }
-            })
+                move |params, query| async move { get_gallery(params, query, &collections).await }
+            }).layer(RateLimitLayer::new(Limit::from_env("GALLERY", 30)))
         )
 }
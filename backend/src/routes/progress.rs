@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use axum::{ extract::{ self, Path }, response::IntoResponse, routing::get, Json, Router };
+use serde_json::json;
+
+use crate::{ auth::Identity, progress, types::{ ProgressUpdate, StatusCodes }, utils::Collections };
+
+pub async fn get_progress(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    match progress::get_progress(&id, &user, collections).await {
+        Ok(progress) => Json(json!({"status": StatusCodes::Success, "progress": progress})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+/// REST counterpart of the `update_progress` socket event.
+pub async fn update_progress(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<ProgressUpdate>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    match progress::record_progress(&id, &user, body, collections).await {
+        Ok(progress) => Json(json!({"status": StatusCodes::Success, "progress": progress})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new().route(
+        "/{id}/progress",
+        get({
+            let collections = Arc::clone(&collections);
+            move |params, identity| async move { get_progress(params, identity, &collections).await }
+        }).post({
+            let collections = Arc::clone(&collections);
+            move |params, identity, body| async move {
+                update_progress(params, identity, body, &collections).await
+            }
+        })
+    )
+}
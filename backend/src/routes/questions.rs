@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use axum::{ extract::{ self, Path }, response::IntoResponse, routing::post, Json, Router };
+use serde_json::json;
+
+use crate::{ auth::Identity, chat, moderation, types::{ Question, StatusCodes }, utils::Collections };
+
+/// Non-streaming fallback for the `ask_question` socket event.
+pub async fn ask(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<Question>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    if let Err(status) = moderation::check_banned(&identity, collections).await {
+        return Json(json!({"status": status}));
+    }
+    match chat::answer_question(&id, &identity, body, collections, |_| {}).await {
+        Ok(answer) => Json(json!({"status": StatusCodes::Success, "answer": answer})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn get_questions(
+    Path(id): Path<String>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    match chat::get_conversation(&id, &user, collections).await {
+        Ok(messages) => Json(json!({"status": StatusCodes::Success, "messages": messages})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new().route(
+        "/{id}/questions",
+        post({
+            let collections = Arc::clone(&collections);
+            move |params, identity, body| async move { ask(params, identity, body, &collections).await }
+        })
+            .layer(chat::QUESTION_LIMIT.clone())
+            .get({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move {
+                    get_questions(params, identity, &collections).await
+                }
+            })
+    )
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use axum::{ extract::{ self, Path }, response::IntoResponse, routing::post, Json, Router };
+use mongodb::bson::{ self, doc, oid::ObjectId, DateTime };
+use serde_json::json;
+
+use crate::{
+    auth::Identity,
+    moderation,
+    ratelimit::{ Limit, RateLimitLayer },
+    types::{ NewReport, Report, ReportStatus, StatusCodes },
+    utils::Collections,
+};
+
+const MAX_DETAILS_CHARS: usize = 1000;
+
+/// Reports lesson `id`, or one of its steps, to the moderators. Reporting the same
+/// thing again while the first report is open returns the open one.
+pub async fn report(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<NewReport>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let details = body.details
+        .map(|d| d.trim().to_string())
+        .filter(|d| !d.is_empty());
+    if details.as_ref().is_some_and(|d| d.chars().count() > MAX_DETAILS_CHARS) {
+        return Json(json!({"status": StatusCodes::InvalidData}));
+    }
+    if let Err(status) = moderation::check_banned(&identity, collections).await {
+        return Json(json!({"status": status}));
+    }
+    let lesson = match collections.lessons.find_one(doc! { "_id": oid }).await {
+        Ok(Some(lesson)) => lesson,
+        Ok(None) => {
+            return Json(json!({"status": StatusCodes::LessonNotFound}));
+        }
+        Err(_) => {
+            return Json(json!({"status": StatusCodes::GenericError}));
+        }
+    };
+    if let Some(step) = body.step && step as usize >= lesson.steps.len() {
+        return Json(json!({"status": StatusCodes::InvalidNumber}));
+    }
+
+    let ip_hash = identity.ip_hash();
+    let reporter = match (&identity.user, &ip_hash) {
+        (Some(user), _) => Some(doc! { "user": user }),
+        (None, Some(ip_hash)) => Some(doc! { "user": null, "ip_hash": ip_hash }),
+        (None, None) => None,
+    };
+    if let Some(mut filter) = reporter {
+        filter.extend(
+            doc! {
+                "lesson": oid,
+                "step": body.step,
+                "status": bson::to_bson(&ReportStatus::Open).unwrap(),
+            }
+        );
+        match collections.reports.find_one(filter).await {
+            Ok(Some(open)) => {
+                return Json(json!({"status": StatusCodes::Success, "id": open.id.map(|id| id.to_string())}));
+            }
+            Ok(None) => {}
+            Err(_) => {
+                return Json(json!({"status": StatusCodes::GenericError}));
+            }
+        }
+    }
+
+    let report = Report {
+        id: None,
+        lesson: oid,
+        step: body.step,
+        reason: body.reason,
+        details,
+        user: identity.user,
+        ip_hash,
+        status: ReportStatus::Open,
+        created_at: DateTime::now(),
+        resolved_at: None,
+    };
+    match collections.reports.insert_one(&report).await {
+        Ok(inserted) => {
+            let id = inserted.inserted_id.as_object_id().map(|id| id.to_string());
+            Json(json!({"status": StatusCodes::Success, "id": id}))
+        }
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new().route(
+        "/{id}/report",
+        post({
+            let collections = Arc::clone(&collections);
+            move |params, identity, body| async move { report(params, identity, body, &collections).await }
+        }).layer(RateLimitLayer::new(Limit::from_env("REPORTS", 10)))
+    )
+}
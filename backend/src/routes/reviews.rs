@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{ self, Path, Query },
+    response::IntoResponse,
+    routing::{ get, post },
+    Json,
+    Router,
+};
+use mongodb::bson::oid::ObjectId;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{ auth::Identity, review, types::{ Grade, StatusCodes }, utils::Collections };
+
+const MAX_DUE_ITEMS: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct DueQuery {
+    /// How many due items to return.
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+fn default_limit() -> u32 {
+    20
+}
+
+pub async fn get_due(
+    identity: Identity,
+    Query(query): Query<DueQuery>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    if query.limit == 0 || query.limit > MAX_DUE_ITEMS {
+        return Json(json!({"status": StatusCodes::InvalidNumber}));
+    }
+    match review::due_items(&user, i64::from(query.limit), collections).await {
+        Ok(items) => Json(json!({"status": StatusCodes::Success, "items": items})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn grade(
+    Path(item): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<Grade>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    match review::grade_item(&user, &item, body.grade, collections).await {
+        Ok(item) => Json(json!({"status": StatusCodes::Success, "item": item})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn get_stats(identity: Identity, collections: &Collections) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    match review::stats(&user, collections).await {
+        Ok(stats) => Json(json!({"status": StatusCodes::Success, "stats": stats})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn enroll(Path(id): Path<String>, identity: Identity, collections: &Collections) -> impl IntoResponse + use<> {
+    let Some(user) = identity.user else {
+        return Json(json!({"status": StatusCodes::UserNotFound}));
+    };
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    match review::enroll_lesson(&user, oid, collections).await {
+        Ok(added) => Json(json!({"status": StatusCodes::Success, "added": added})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new()
+        .route(
+            "/due",
+            get({
+                let collections = Arc::clone(&collections);
+                move |identity, query| async move { get_due(identity, query, &collections).await }
+            })
+        )
+        .route(
+            "/stats",
+            get({
+                let collections = Arc::clone(&collections);
+                move |identity| async move { get_stats(identity, &collections).await }
+            })
+        )
+        .route(
+            "/lessons/{id}",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { enroll(params, identity, &collections).await }
+            })
+        )
+        .route(
+            "/{item}",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move { grade(params, identity, body, &collections).await }
+            })
+        )
+}
@@ -0,0 +1,398 @@
+use std::{ collections::BTreeMap, sync::Arc };
+
+use axum::{ extract::{ self, Path }, response::IntoResponse, routing::{ get, patch, post }, Json, Router };
+use futures::TryStreamExt;
+use mongodb::bson::{ self, doc, oid::ObjectId, DateTime, Document };
+use serde_json::{ json, Value };
+use similar::{ ChangeTag, TextDiff };
+
+use crate::{
+    auth::Identity,
+    duration,
+    moderation::{ self, Subject, MODERATION },
+    readability,
+    types::{ ContentEdit, LessonRecord, LessonStatus, Public, Revision, StatusCodes },
+    utils::{ self, Collections },
+};
+
+/// Loads lesson `id` for editing by its owner `identity`; lessons without an owner are
+/// read-only. Lessons that are still being generated cannot be edited, since the
+/// pipeline would overwrite the changes.
+async fn load_editable(
+    id: &str,
+    identity: &Identity,
+    collections: &Collections
+) -> Result<(ObjectId, LessonRecord), StatusCodes> {
+    let oid = ObjectId::parse_str(id).map_err(|_| StatusCodes::InvalidID)?;
+    moderation::check_banned(identity, collections).await?;
+    let lesson = collections.lessons
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::LessonNotFound)?;
+    if !lesson.is_owned_by(identity.user.as_deref()) {
+        return Err(StatusCodes::Unauthorized);
+    }
+    if lesson.status == LessonStatus::Generating {
+        return Err(StatusCodes::InvalidState);
+    }
+    Ok((oid, lesson))
+}
+
+/// Current values of the fields `edit` touches, or `None` if it sets a field the
+/// target does not have or the step does not exist.
+fn current_content(lesson: &LessonRecord, step: Option<usize>, edit: &ContentEdit) -> Option<ContentEdit> {
+    let pick = |set: bool, value: &str| set.then(|| value.to_string());
+    match step {
+        None => {
+            if edit.explanation.is_some() || edit.speech.is_some() || edit.references.is_some() {
+                return None;
+            }
+            Some(ContentEdit {
+                title: pick(edit.title.is_some(), &lesson.title),
+                description: pick(edit.description.is_some(), &lesson.description),
+                ..Default::default()
+            })
+        }
+        Some(index) => {
+            if edit.description.is_some() {
+                return None;
+            }
+            let step = lesson.steps.get(index)?.as_ref()?;
+            Some(ContentEdit {
+                title: pick(edit.title.is_some(), &step.title),
+                explanation: pick(edit.explanation.is_some(), &step.explanation),
+                speech: pick(edit.speech.is_some(), &step.speech),
+                references: edit.references.is_some().then(|| step.references.clone()),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// Drops the fields whose value would not change, returning what is left of `before`
+/// and `after`.
+fn changed_fields(before: ContentEdit, after: ContentEdit) -> (ContentEdit, ContentEdit) {
+    fn keep<T: PartialEq>(before: Option<T>, after: Option<T>) -> (Option<T>, Option<T>) {
+        if before == after { (None, None) } else { (before, after) }
+    }
+    let (title_before, title_after) = keep(before.title, after.title);
+    let (description_before, description_after) = keep(before.description, after.description);
+    let (explanation_before, explanation_after) = keep(before.explanation, after.explanation);
+    let (speech_before, speech_after) = keep(before.speech, after.speech);
+    let (references_before, references_after) = keep(before.references, after.references);
+    (
+        ContentEdit {
+            title: title_before,
+            description: description_before,
+            explanation: explanation_before,
+            speech: speech_before,
+            references: references_before,
+        },
+        ContentEdit {
+            title: title_after,
+            description: description_after,
+            explanation: explanation_after,
+            speech: speech_after,
+            references: references_after,
+        },
+    )
+}
+
+/// Applies `edit` to the lesson or one of its steps and records it as a revision.
+/// Returns `None` when nothing actually changed. The new text is screened like a
+/// lesson request: blocked edits are refused and flagged ones hide the lesson.
+async fn apply_edit(
+    oid: ObjectId,
+    lesson: &LessonRecord,
+    step: Option<usize>,
+    edit: ContentEdit,
+    identity: &Identity,
+    rollback_of: Option<ObjectId>,
+    collections: &Collections
+) -> Result<Option<Revision>, StatusCodes> {
+    if edit == ContentEdit::default() {
+        return Err(StatusCodes::InvalidData);
+    }
+    if edit.title.as_deref().is_some_and(|t| t.trim().is_empty()) {
+        return Err(StatusCodes::InvalidData);
+    }
+    let current = current_content(lesson, step, &edit).ok_or(match step {
+        Some(index) if index >= lesson.steps.len() => StatusCodes::InvalidNumber,
+        _ => StatusCodes::InvalidData,
+    })?;
+    let (before, after) = changed_fields(current, edit);
+    if after == ContentEdit::default() {
+        return Ok(None);
+    }
+    let text = field_texts(&after).into_values().collect::<Vec<_>>().join("\n");
+    let ip_hash = identity.ip_hash();
+    let subject = Subject { lesson: Some(oid), user: identity.user.as_deref(), ip_hash: ip_hash.as_deref() };
+    let target = if step.is_some() { "step" } else { "lesson" };
+    match MODERATION.screen_request(subject, target, step.map(|i| i as u32), &text, collections).await {
+        Ok(None) => {}
+        Ok(Some(verdict)) => utils::store_verdict(oid, &verdict, collections).await.map_err(|_| StatusCodes::GenericError)?,
+        Err(_) => {
+            return Err(StatusCodes::Rejected);
+        }
+    }
+
+    let prefix = step.map(|i| format!("steps.{}.", i)).unwrap_or_default();
+    let mut set = Document::new();
+    for (field, value) in bson::to_document(&after).map_err(|_| StatusCodes::GenericError)? {
+        set.insert(format!("{}{}", prefix, field), value);
+    }
+    // Edited text takes a different time to read or narrate, and reads differently
+    if
+        let Some(index) = step &&
+        (after.explanation.is_some() || after.speech.is_some()) &&
+        let Some(Some(edited)) = lesson.steps.get(index)
+    {
+        let mut edited = edited.clone();
+        if let Some(explanation) = &after.explanation {
+            edited.reading_time = duration::reading_seconds(edited.media_type, explanation);
+            // Hand edits are scored but never rewritten
+            edited.readability = readability::assess(explanation, &lesson.difficulty);
+            set.insert(format!("{}readability", prefix), bson::to_bson(&edited.readability).unwrap());
+        }
+        if let Some(speech) = &after.speech {
+            // The stored audio no longer matches the new narration
+            edited.listening_time = duration::listening_seconds(speech);
+        }
+        set.insert(format!("{}reading_time", prefix), edited.reading_time);
+        set.insert(format!("{}listening_time", prefix), edited.listening_time);
+        let mut steps = lesson.steps.clone();
+        steps[index] = Some(edited);
+        set.insert("duration", duration::lesson_seconds(&steps));
+    }
+    let generating = bson::to_bson(&LessonStatus::Generating).unwrap();
+    let result = collections.lessons
+        .update_one(doc! { "_id": oid, "status": { "$ne": generating } }, doc! { "$set": set }).await
+        .map_err(|_| StatusCodes::GenericError)?;
+    if result.matched_count == 0 {
+        return Err(StatusCodes::InvalidState);
+    }
+
+    let mut revision = Revision {
+        id: None,
+        lesson: oid,
+        step: step.map(|i| i as u32),
+        author: identity.user.clone(),
+        created_at: DateTime::now(),
+        rollback_of,
+        before,
+        after,
+    };
+    let inserted = collections.revisions.insert_one(&revision).await.map_err(|_| StatusCodes::GenericError)?;
+    revision.id = inserted.inserted_id.as_object_id();
+    Ok(Some(revision))
+}
+
+fn edit_response(result: Result<Option<Revision>, StatusCodes>) -> Json<Value> {
+    match result {
+        Ok(revision) => Json(json!({"status": StatusCodes::Success, "revision": revision.as_ref().map(Public)})),
+        Err(status) => Json(json!({"status": status})),
+    }
+}
+
+pub async fn edit_lesson(
+    Path(id): Path<String>,
+    identity: Identity,
+    extract::Json(body): extract::Json<ContentEdit>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let result = match load_editable(&id, &identity, collections).await {
+        Ok((oid, lesson)) => apply_edit(oid, &lesson, None, body, &identity, None, collections).await,
+        Err(status) => Err(status),
+    };
+    edit_response(result)
+}
+
+pub async fn edit_step(
+    Path((id, index)): Path<(String, usize)>,
+    identity: Identity,
+    extract::Json(body): extract::Json<ContentEdit>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let result = match load_editable(&id, &identity, collections).await {
+        Ok((oid, lesson)) => apply_edit(oid, &lesson, Some(index), body, &identity, None, collections).await,
+        Err(status) => Err(status),
+    };
+    edit_response(result)
+}
+
+pub async fn get_revisions(Path(id): Path<String>, collections: &Collections) -> impl IntoResponse + use<> {
+    let Ok(oid) = ObjectId::parse_str(&id) else {
+        return Json(json!({"status": StatusCodes::InvalidID}));
+    };
+    let revisions: Result<Vec<Revision>, _> = match
+        collections.revisions.find(doc! { "lesson": oid }).sort(doc! { "_id": -1 }).await
+    {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match revisions {
+        Ok(revisions) => {
+            let revisions: Vec<Public<_>> = revisions.iter().map(Public).collect();
+            Json(json!({"status": StatusCodes::Success, "revisions": revisions}))
+        }
+        Err(_) => Json(json!({"status": StatusCodes::GenericError})),
+    }
+}
+
+async fn find_revision(id: &str, revision: &str, collections: &Collections) -> Result<Revision, StatusCodes> {
+    let lesson = ObjectId::parse_str(id).map_err(|_| StatusCodes::InvalidID)?;
+    let revision = ObjectId::parse_str(revision).map_err(|_| StatusCodes::InvalidID)?;
+    collections.revisions
+        .find_one(doc! { "_id": revision, "lesson": lesson }).await
+        .map_err(|_| StatusCodes::GenericError)?
+        .ok_or(StatusCodes::InvalidID)
+}
+
+/// Text form of each field set in `content`, with references one per line.
+fn field_texts(content: &ContentEdit) -> BTreeMap<&'static str, String> {
+    let mut fields = BTreeMap::new();
+    let text_fields = [
+        ("title", &content.title),
+        ("description", &content.description),
+        ("explanation", &content.explanation),
+        ("speech", &content.speech),
+    ];
+    for (name, value) in text_fields {
+        if let Some(value) = value {
+            fields.insert(name, value.clone());
+        }
+    }
+    if let Some(references) = &content.references {
+        fields.insert("references", references.join("\n"));
+    }
+    fields
+}
+
+/// Word level diff as runs of `{op, text}` with `op` one of `equal`, `delete` and
+/// `insert`.
+fn diff_text(before: &str, after: &str) -> Vec<Value> {
+    let mut runs: Vec<(ChangeTag, String)> = Vec::new();
+    for change in TextDiff::from_words(before, after).iter_all_changes() {
+        match runs.last_mut() {
+            Some((tag, text)) if *tag == change.tag() => text.push_str(change.value()),
+            _ => runs.push((change.tag(), change.value().to_string())),
+        }
+    }
+    runs.into_iter()
+        .map(|(tag, text)| {
+            let op = match tag {
+                ChangeTag::Equal => "equal",
+                ChangeTag::Delete => "delete",
+                ChangeTag::Insert => "insert",
+            };
+            json!({"op": op, "text": text})
+        })
+        .collect()
+}
+
+pub async fn get_revision_diff(
+    Path((id, revision)): Path<(String, String)>,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let revision = match find_revision(&id, &revision, collections).await {
+        Ok(revision) => revision,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let before = field_texts(&revision.before);
+    let diff: BTreeMap<_, _> = field_texts(&revision.after)
+        .into_iter()
+        .map(|(field, after)| {
+            let before = before.get(field).map(String::as_str).unwrap_or_default();
+            (field, diff_text(before, &after))
+        })
+        .collect();
+    Json(json!({"status": StatusCodes::Success, "revision": Public(&revision), "diff": diff}))
+}
+
+/// Restores the lesson or step to how it was before `revision`, undoing it and every
+/// later revision of the same target. The rollback is itself stored as a revision.
+pub async fn rollback(
+    Path((id, revision)): Path<(String, String)>,
+    identity: Identity,
+    collections: &Collections
+) -> impl IntoResponse + use<> {
+    let target = match find_revision(&id, &revision, collections).await {
+        Ok(revision) => revision,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let (oid, lesson) = match load_editable(&id, &identity, collections).await {
+        Ok(loaded) => loaded,
+        Err(status) => {
+            return Json(json!({"status": status}));
+        }
+    };
+    let filter = doc! { "lesson": oid, "step": target.step.map(i64::from), "_id": { "$gte": target.id } };
+    let later: Result<Vec<Revision>, _> = match
+        collections.revisions.find(filter).sort(doc! { "_id": -1 }).await
+    {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    let Ok(later) = later else {
+        return Json(json!({"status": StatusCodes::GenericError}));
+    };
+    // Newest first, so the oldest value of each field is the one that sticks
+    let mut restore = ContentEdit::default();
+    for revision in later {
+        let before = revision.before;
+        restore.title = before.title.or(restore.title);
+        restore.description = before.description.or(restore.description);
+        restore.explanation = before.explanation.or(restore.explanation);
+        restore.speech = before.speech.or(restore.speech);
+        restore.references = before.references.or(restore.references);
+    }
+    let step = target.step.map(|i| i as usize);
+    edit_response(apply_edit(oid, &lesson, step, restore, &identity, target.id, collections).await)
+}
+
+pub fn get_routes(collections: Arc<Collections>) -> Router {
+    Router::new()
+        .route(
+            "/{id}",
+            patch({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move {
+                    edit_lesson(params, identity, body, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/steps/{index}",
+            patch({
+                let collections = Arc::clone(&collections);
+                move |params, identity, body| async move {
+                    edit_step(params, identity, body, &collections).await
+                }
+            })
+        )
+        .route(
+            "/{id}/revisions",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params| async move { get_revisions(params, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/revisions/{revision}/diff",
+            get({
+                let collections = Arc::clone(&collections);
+                move |params| async move { get_revision_diff(params, &collections).await }
+            })
+        )
+        .route(
+            "/{id}/revisions/{revision}/rollback",
+            post({
+                let collections = Arc::clone(&collections);
+                move |params, identity| async move { rollback(params, identity, &collections).await }
+            })
+        )
+}
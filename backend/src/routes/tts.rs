@@ -6,7 +6,7 @@ use serde_json::json;
 use crate::{ types::StatusCodes, utils::Collections };
 
 pub async fn get_tts(Path(id): Path<String>, collections: &Collections) -> Response {
-    if id.len() == 0 {
+    if id.is_empty() {
         return Json(json!({"status": StatusCodes::InvalidID})).into_response();
     }
     let tts = collections.tts
@@ -17,13 +17,12 @@ pub async fn get_tts(Path(id): Path<String>, collections: &Collections) -> Respo
         Some(tts) => {
             // send raw mp3 with headers
             let data = tts.data;
-            let response = axum::response::Response
+            axum::response::Response
                 ::builder()
                 .header("Content-Type", "audio/mpeg")
                 .header("Content-Length", data.len())
                 .body(axum::body::Body::from(data))
-                .unwrap();
-            return response;
+                .unwrap()
         }
         None => Json(json!({"status": StatusCodes::AudioNotFound})).into_response(),
     }
@@ -39,7 +38,7 @@ pub fn get_routes(collections: Arc<Collections>) -> Router {
             "/{id}",
             get({
                 let collections = Arc::clone(&collections);
-                move |params| async move { get_tts(params, &*collections).await }
+                move |params| async move { get_tts(params, &collections).await }
             })
         )
 }
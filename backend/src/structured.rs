@@ -0,0 +1,511 @@
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+use serde_json::{ json, Value };
+use crate::types::{ Flashcard, GlossaryEntry, ModerationAction, OutlineItem };
+
+/// A typed response the model is asked to produce. The schema is sent as the
+/// `response_format` (see `strict_schema`) and also checked locally in full, since
+/// not every provider enforces it.
+pub trait StructuredOutput: DeserializeOwned {
+    const NAME: &'static str;
+
+    fn schema() -> Value;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Outline {
+    pub title: String,
+    pub description: String,
+    pub objectives: Vec<String>,
+    pub prerequisites: Vec<String>,
+    pub outline: Vec<OutlineItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Explanation {
+    pub explanation: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct References {
+    pub references: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WikipediaReference {
+    pub wikipedia_url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Flashcards {
+    pub cards: Vec<Flashcard>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Glossary {
+    pub terms: Vec<GlossaryEntry>,
+}
+
+impl StructuredOutput for Outline {
+    const NAME: &'static str = "outline";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "title": {"type": "string", "minLength": 1},
+                "description": {"type": "string"},
+                "objectives": {
+                    "type": "array",
+                    "maxItems": 8,
+                    "items": {"type": "string", "minLength": 1}
+                },
+                "prerequisites": {
+                    "type": "array",
+                    "maxItems": 8,
+                    "items": {"type": "string", "minLength": 1}
+                },
+                "outline": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "title": {"type": "string"},
+                            "media_type": {"type": "string", "enum": ["text", "image"]},
+                            "prompt": {"type": "string"},
+                            "speech": {"type": "string"}
+                        },
+                        "required": ["title", "media_type", "prompt", "speech"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["title", "description", "objectives", "prerequisites", "outline"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl StructuredOutput for Explanation {
+    const NAME: &'static str = "explanation";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "explanation": {"type": "string", "minLength": 1}
+            },
+            "required": ["explanation"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl StructuredOutput for References {
+    const NAME: &'static str = "references";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "references": {
+                    "type": "array",
+                    "maxItems": 3,
+                    "items": {"type": "string"}
+                }
+            },
+            "required": ["references"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl StructuredOutput for WikipediaReference {
+    const NAME: &'static str = "wikipedia_ref";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "wikipedia_url": {"type": "string", "minLength": 1}
+            },
+            "required": ["wikipedia_url"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl StructuredOutput for Flashcards {
+    const NAME: &'static str = "flashcards";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "cards": {
+                    "type": "array",
+                    "minItems": 1,
+                    "maxItems": 30,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "front": {"type": "string", "minLength": 1},
+                            "back": {"type": "string", "minLength": 1},
+                            "step": {"type": "integer"}
+                        },
+                        "required": ["front", "back", "step"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["cards"],
+            "additionalProperties": false
+        })
+    }
+}
+
+impl StructuredOutput for Glossary {
+    const NAME: &'static str = "glossary";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "terms": {
+                    "type": "array",
+                    "maxItems": 40,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "term": {"type": "string", "minLength": 1},
+                            "definition": {"type": "string", "minLength": 1},
+                            "step": {"type": "integer"}
+                        },
+                        "required": ["term", "definition", "step"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["terms"],
+            "additionalProperties": false
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ModerationResult {
+    pub action: ModerationAction,
+    pub categories: Vec<String>,
+    pub reason: String,
+}
+
+impl StructuredOutput for ModerationResult {
+    const NAME: &'static str = "moderation";
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "action": {"type": "string", "enum": ["allow", "flag", "block"]},
+                "categories": {"type": "array", "items": {"type": "string"}},
+                "reason": {"type": "string"}
+            },
+            "required": ["action", "categories", "reason"],
+            "additionalProperties": false
+        })
+    }
+}
+
+/// Turns raw model output into `T`: pulls the JSON object out of any surrounding
+/// fences or chatter, repairs the usual escaping mistakes, then validates it against
+/// `T::schema()` before deserializing. The error is phrased so it can be sent back
+/// to the model as-is.
+pub fn parse<T: StructuredOutput>(raw: &str) -> Result<T, String> {
+    let candidate = extract_json(raw).ok_or_else(||
+        "The response does not contain a JSON object".to_string()
+    )?;
+    let value: Value = serde_json
+        ::from_str(&repair_json(candidate))
+        .map_err(|e| format!("The response is not valid JSON: {}", e))?;
+    validate(&value, &T::schema(), "$")?;
+    serde_json::from_value(value).map_err(|e| format!("The response does not match the schema: {}", e))
+}
+
+/// Best-effort value of the string field `key` in a JSON object that is still being
+/// streamed, decoded as far as it has arrived. Used to show partial output; the final
+/// value always comes from `parse`.
+pub fn partial_string_field(raw: &str, key: &str) -> Option<String> {
+    let quoted_key = format!("\"{}\"", key);
+    let start = raw.find(&quoted_key)? + quoted_key.len();
+    let value = raw[start..].trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' =>
+                match chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => {}
+                    Some(c @ ('"' | '\\' | '/')) => out.push(c),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) if hex.len() == 4 => out.push(c),
+                            // Cut off mid-escape
+                            _ if hex.len() < 4 => break,
+                            _ => {
+                                out.push_str("\\u");
+                                out.push_str(&hex);
+                            }
+                        }
+                    }
+                    // Unescaped LaTeX such as `\frac`, kept as written
+                    Some(c) => {
+                        out.push('\\');
+                        out.push(c);
+                    }
+                    None => break,
+                }
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// Length and count limits that several providers reject in a strict
+/// `response_format`. They stay in the local schema and `validate` enforces them.
+const LOCAL_ONLY_KEYWORDS: [&str; 3] = ["minLength", "minItems", "maxItems"];
+
+/// `schema` without the keywords in `LOCAL_ONLY_KEYWORDS`, for sending to the provider.
+pub fn strict_schema(schema: &Value) -> Value {
+    match schema {
+        Value::Object(object) =>
+            Value::Object(
+                object
+                    .iter()
+                    .filter(|(key, _)| !LOCAL_ONLY_KEYWORDS.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), strict_schema(value)))
+                    .collect()
+            ),
+        Value::Array(items) => Value::Array(items.iter().map(strict_schema).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Returns the first balanced `{...}` in `raw`, skipping over braces inside strings.
+fn extract_json(raw: &str) -> Option<&str> {
+    let start = raw.find('{')?;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in raw[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => {
+                    escaped = false;
+                }
+                '\\' => {
+                    escaped = true;
+                }
+                '"' => {
+                    in_string = false;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+            }
+            '{' => {
+                depth += 1;
+            }
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&raw[start..start + i + 1]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Fixes escapes models commonly get wrong inside JSON strings: lone backslashes
+/// from KaTeX (`\frac`, `\alpha`), LaTeX commands that happen to look like valid
+/// escapes inside `$...$` (`\times`, `\beta`, `\nabla`) and raw control characters.
+/// Trailing commas before `}` or `]` are dropped.
+fn repair_json(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    let mut chars = json.chars().peekable();
+    let mut in_string = false;
+    let mut in_math = false;
+    while let Some(c) = chars.next() {
+        if !in_string {
+            if c == '"' {
+                in_string = true;
+                in_math = false;
+            }
+            let trailing_comma =
+                c == ',' &&
+                matches!(chars.clone().find(|c| !c.is_whitespace()), Some('}' | ']'));
+            if !trailing_comma {
+                out.push(c);
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = false;
+                out.push(c);
+            }
+            '$' => {
+                in_math = !in_math;
+                out.push(c);
+            }
+            '\\' => {
+                match chars.peek().copied() {
+                    Some(next @ ('"' | '\\' | '/')) => {
+                        chars.next();
+                        out.push('\\');
+                        out.push(next);
+                    }
+                    Some(next @ ('b' | 'f' | 'n' | 'r' | 't')) => {
+                        chars.next();
+                        let latex = in_math && chars.peek().is_some_and(|c| c.is_ascii_alphabetic());
+                        out.push_str(if latex { "\\\\" } else { "\\" });
+                        out.push(next);
+                    }
+                    Some('u') => {
+                        let hex: String = chars.clone().skip(1).take(4).collect();
+                        let valid = hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit());
+                        out.push_str(if valid { "\\" } else { "\\\\" });
+                    }
+                    _ => out.push_str("\\\\"),
+                }
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Validates `value` against the subset of JSON schema used by our response formats.
+fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(options) = schema["enum"].as_array() && !options.contains(value) {
+        return Err(format!("{} must be one of {}", path, Value::Array(options.clone())));
+    }
+    match schema["type"].as_str() {
+        Some("object") => {
+            let object = value
+                .as_object()
+                .ok_or_else(|| format!("{} must be an object", path))?;
+            let properties = schema["properties"].as_object();
+            for key in schema["required"].as_array().into_iter().flatten() {
+                let key = key.as_str().unwrap_or_default();
+                if !object.contains_key(key) {
+                    return Err(format!("{} is missing the required field '{}'", path, key));
+                }
+            }
+            for (key, field) in object {
+                match properties.and_then(|p| p.get(key)) {
+                    Some(field_schema) => validate(field, field_schema, &format!("{}.{}", path, key))?,
+                    None if schema["additionalProperties"] == Value::Bool(false) => {
+                        return Err(format!("{} has an unexpected field '{}'", path, key));
+                    }
+                    None => {}
+                }
+            }
+        }
+        Some("array") => {
+            let items = value.as_array().ok_or_else(|| format!("{} must be an array", path))?;
+            if let Some(min) = schema["minItems"].as_u64() && (items.len() as u64) < min {
+                return Err(format!("{} must have at least {} items", path, min));
+            }
+            if let Some(max) = schema["maxItems"].as_u64() && (items.len() as u64) > max {
+                return Err(format!("{} must have at most {} items", path, max));
+            }
+            for (i, item) in items.iter().enumerate() {
+                validate(item, &schema["items"], &format!("{}[{}]", path, i))?;
+            }
+        }
+        Some("string") => {
+            let text = value.as_str().ok_or_else(|| format!("{} must be a string", path))?;
+            if let Some(min) = schema["minLength"].as_u64() && (text.trim().chars().count() as u64) < min {
+                return Err(format!("{} must not be empty", path));
+            }
+        }
+        Some("integer") if !value.is_i64() && !value.is_u64() => {
+            return Err(format!("{} must be an integer", path));
+        }
+        Some("number") if !value.is_number() => {
+            return Err(format!("{} must be a number", path));
+        }
+        Some("boolean") if !value.is_boolean() => {
+            return Err(format!("{} must be a boolean", path));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_json_from_fences_and_chatter() {
+        let raw = "Claro, aquí está:\n```json\n{\"explanation\": \"Las {llaves} cuentan\"}\n```\nEspero que sirva.";
+        assert_eq!(extract_json(raw), Some("{\"explanation\": \"Las {llaves} cuentan\"}"));
+        let parsed: Explanation = parse(raw).unwrap();
+        assert_eq!(parsed.explanation, "Las {llaves} cuentan");
+    }
+
+    #[test]
+    fn truncated_json_is_rejected() {
+        let raw = "{\"explanation\": \"La respuesta se cor";
+        assert_eq!(extract_json(raw), None);
+        assert!(parse::<Explanation>(raw).is_err());
+    }
+
+    #[test]
+    fn repairs_trailing_commas() {
+        let raw = "{\"references\": [\"https://es.wikipedia.org/wiki/Sol\", ], }";
+        let parsed: References = parse(raw).unwrap();
+        assert_eq!(parsed.references, vec!["https://es.wikipedia.org/wiki/Sol"]);
+        // Commas inside strings are kept
+        assert_eq!(repair_json("{\"a\": \"x, }\"}"), "{\"a\": \"x, }\"}");
+    }
+
+    #[test]
+    fn repairs_latex_and_control_characters() {
+        let raw = "{\"explanation\": \"Sea $\\frac{a}{b} \\times \\beta$\n\\alpha\"}";
+        let parsed: Explanation = parse(raw).unwrap();
+        assert_eq!(parsed.explanation, "Sea $\\frac{a}{b} \\times \\beta$\n\\alpha");
+    }
+
+    #[test]
+    fn validation_enforces_local_limits() {
+        assert!(parse::<Explanation>("{\"explanation\": \"  \"}").unwrap_err().contains("must not be empty"));
+        let refs = "{\"references\": [\"a\", \"b\", \"c\", \"d\"]}";
+        assert!(parse::<References>(refs).unwrap_err().contains("at most 3"));
+    }
+
+    #[test]
+    fn strict_schema_drops_local_only_keywords() {
+        let schema = strict_schema(&Outline::schema());
+        let text = schema.to_string();
+        for keyword in LOCAL_ONLY_KEYWORDS {
+            assert!(!text.contains(keyword), "{} left in {}", keyword, text);
+        }
+        assert_eq!(schema["required"], Outline::schema()["required"]);
+    }
+}
@@ -1,5 +1,6 @@
-use mongodb::bson::doc;
-use serde::{ Deserialize, Deserializer, Serialize };
+use std::collections::BTreeMap;
+use mongodb::bson::{ doc, oid::ObjectId, DateTime };
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
 use strum_macros::AsRefStr;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
@@ -10,9 +11,9 @@ pub enum Difficulty {
     University = 2,
 }
 
-impl Into<String> for Difficulty {
-    fn into(self) -> String {
-        match self {
+impl From<Difficulty> for String {
+    fn from(difficulty: Difficulty) -> String {
+        match difficulty {
             Difficulty::Elementary => "Elementary".to_string(),
             Difficulty::HighSchool => "High School".to_string(),
             Difficulty::University => "University".to_string(),
@@ -20,19 +21,465 @@ impl Into<String> for Difficulty {
     }
 }
 
+/// Body for starting a lesson. `difficulty` can be left out when `audience` says
+/// enough to pick one.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
 pub struct Lesson {
     pub prompt: String,
-    #[serde(deserialize_with = "deserialize_difficulty")]
+    #[serde(default, deserialize_with = "deserialize_optional_difficulty")]
+    pub difficulty: Option<Difficulty>,
+    #[serde(default)]
+    pub audience: Option<Audience>,
+    #[serde(default)]
+    pub mode: LessonMode,
+}
+
+/// Body for remixing an existing lesson. At least one of `difficulty`, `audience`
+/// and `instructions` must be given.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Remix {
+    #[serde(default, deserialize_with = "deserialize_optional_difficulty")]
+    pub difficulty: Option<Difficulty>,
+    #[serde(default)]
+    pub audience: Option<Audience>,
+    #[serde(default)]
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub mode: LessonMode,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Expertise {
+    /// Works in the field.
+    Professional,
+    /// Specialist who wants depth and rigor.
+    Expert,
+}
+
+/// Who a lesson is for, more precisely than `Difficulty`. Every field is optional and
+/// the prompts describe whichever are given.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Audience {
+    /// School grade, from 1 to 12.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grade: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<u8>,
+    /// For adult learners past school level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expertise: Option<Expertise>,
+    /// Learner profile notes such as "visual learner" or "dyslexia-friendly".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+}
+
+impl Audience {
+    pub const MAX_NOTES: usize = 5;
+    pub const MAX_NOTE_CHARS: usize = 120;
+
+    pub fn is_valid(&self) -> bool {
+        self.grade.is_none_or(|grade| (1..=12).contains(&grade)) &&
+            self.age.is_none_or(|age| (3..=120).contains(&age)) &&
+            self.notes.len() <= Self::MAX_NOTES &&
+            self.notes.iter().all(|note| !note.trim().is_empty() && note.chars().count() <= Self::MAX_NOTE_CHARS)
+    }
+
+    /// The closest of the three levels, used where only those are supported such as
+    /// the readability bands. `None` if only notes are given.
+    pub fn difficulty(&self) -> Option<Difficulty> {
+        if self.expertise.is_some() {
+            return Some(Difficulty::University);
+        }
+        match (self.grade, self.age) {
+            (Some(grade), _) if grade <= 6 => Some(Difficulty::Elementary),
+            (Some(_), _) => Some(Difficulty::HighSchool),
+            (None, Some(age)) if age <= 11 => Some(Difficulty::Elementary),
+            (None, Some(age)) if age <= 17 => Some(Difficulty::HighSchool),
+            (None, Some(_)) => Some(Difficulty::University),
+            (None, None) => None,
+        }
+    }
+
+    /// Spanish description for prompts and for showing in the museum, such as
+    /// "estudiantes de 4.º grado, de 9 años (visual learner)".
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(grade) = self.grade {
+            parts.push(format!("estudiantes de {}.º grado", grade));
+        }
+        if let Some(age) = self.age {
+            parts.push(format!("de {} años", age));
+        }
+        match self.expertise {
+            Some(Expertise::Professional) => parts.push("profesionales del área".to_string()),
+            Some(Expertise::Expert) => parts.push("expertos en el tema".to_string()),
+            None => {}
+        }
+        let mut description = parts.join(", ");
+        if !self.notes.is_empty() {
+            let notes = self.notes
+                .iter()
+                .map(|n| n.trim())
+                .collect::<Vec<_>>()
+                .join("; ");
+            if description.is_empty() {
+                description = format!("estudiantes con este perfil: {}", notes);
+            } else {
+                description.push_str(&format!(" (perfil: {})", notes));
+            }
+        }
+        description
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LessonMode {
+    /// Generate the outline and every step in one go.
+    #[default]
+    Full,
+    /// Stop after the outline so it can be edited before generating the steps.
+    Review,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    #[default]
+    Text,
+    Image,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct OutlineItem {
+    pub title: String,
+    pub media_type: MediaType,
+    pub prompt: String,
+    #[serde(default)]
+    pub speech: String,
+}
+
+/// Body for adding an outline item, appended unless `index` is given.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NewOutlineItem {
+    #[serde(flatten)]
+    pub item: OutlineItem,
+    #[serde(default)]
+    pub index: Option<usize>,
+}
+
+/// Body for editing an outline item; missing fields are left unchanged.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct OutlineItemPatch {
+    pub title: Option<String>,
+    pub media_type: Option<MediaType>,
+    pub prompt: Option<String>,
+    pub speech: Option<String>,
+}
+
+/// Body for reordering an outline: the current index of each item in its new order.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OutlineOrder {
+    pub order: Vec<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Step {
+    pub title: String,
+    #[serde(default)]
+    pub media_type: MediaType,
+    pub image: Option<String>,
+    pub explanation: String,
+    #[serde(default)]
+    pub speech: String,
+    #[serde(default)]
+    pub tts: Option<String>,
+    #[serde(default)]
+    pub references: Vec<String>,
+    /// Model that produced each stage of this step, keyed by `llm::Stage`.
+    #[serde(default)]
+    pub models: BTreeMap<String, String>,
+    /// Ids of lessons that go deeper into this step.
+    #[serde(default)]
+    pub sub_lessons: Vec<String>,
+    /// Estimated seconds to read the explanation.
+    #[serde(default)]
+    pub reading_time: u32,
+    /// Seconds the narration lasts.
+    #[serde(default)]
+    pub listening_time: u32,
+    /// How easy the explanation is to read, if it is long enough to score.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readability: Option<Readability>,
+}
+
+/// Readability of a step's explanation, see `readability::assess`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Readability {
+    /// `es` or `en`, guessed from common words.
+    pub language: String,
+    /// Szigriszt-Pazos perspicuity for Spanish, Flesch reading ease for English.
+    pub score: f64,
+    /// Fernández-Huerta score, for Spanish text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fernandez_huerta: Option<f64>,
+    /// Whether `score` is in the band for the lesson's difficulty.
+    pub in_band: bool,
+    /// Times the model was asked to rewrite the explanation to fit the band.
+    #[serde(default)]
+    pub rewrites: u32,
+}
+
+/// A key term of a lesson and its definition at the lesson's level.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GlossaryEntry {
+    pub term: String,
+    pub definition: String,
+    /// Index of the first step that mentions the term.
+    pub step: u32,
+}
+
+/// A step of another lesson.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct StepRef {
+    pub lesson: ObjectId,
+    pub step: u32,
+}
+
+/// Tokens and provider-reported cost (in OpenRouter credits, i.e. USD) of one or
+/// more chat completions.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct TokenUsage {
+    pub requests: u32,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl TokenUsage {
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Daily usage rollup for one user (`None` for anonymous requests).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct UsageRecord {
+    pub user: Option<String>,
+    /// UTC day in `YYYY-MM-DD` form.
+    pub day: String,
+    #[serde(flatten)]
+    pub usage: TokenUsage,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LessonStatus {
+    Generating,
+    /// Outline generated in review mode, waiting to be approved.
+    Review,
+    /// Lessons stored before statuses were tracked are treated as finished.
+    #[default]
+    Completed,
+    Cancelled,
+    Failed,
+    /// Stopped after using up the lesson's token budget.
+    #[serde(rename = "quota_exceeded")]
+    QuotaExceeded,
+    /// Stopped because moderation blocked generated content, see `LessonRecord::moderation`.
+    Rejected,
+}
+
+/// A lesson as stored in the lessons collection and sent to clients over HTTP and
+/// socket.io.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct LessonRecord {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// Id of the user who requested the lesson, from the `X-User-Id` header.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Salted hash of the requesting address, for per-address quotas and bans. Never
+    /// sent to clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    pub prompt: String,
+    #[serde(serialize_with = "serialize_difficulty", deserialize_with = "deserialize_difficulty")]
     pub difficulty: Difficulty,
-    #[serde(skip)]
+    #[serde(default)]
+    pub status: LessonStatus,
+    #[serde(default)]
     pub title: String,
-    #[serde(skip)]
+    #[serde(default)]
     pub description: String,
-    #[serde(skip)]
-    pub outline: Vec<String>,
-    #[serde(skip)]
-    pub steps: Vec<String>,
+    #[serde(default)]
+    pub outline: Vec<OutlineItem>,
+    /// One slot per outline item, `None` until that step has been generated.
+    #[serde(default)]
+    pub steps: Vec<Option<Step>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wikipedia_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outline_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wikipedia_model: Option<String>,
+    /// Token usage and cost of generating this lesson, keyed by `llm::Stage`.
+    #[serde(default)]
+    pub usage: BTreeMap<String, TokenUsage>,
+    /// The lesson this one was remixed from, whose outline and images it reuses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remixed_from: Option<ObjectId>,
+    /// Extra instructions given when remixing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remix_instructions: Option<String>,
+    /// The step this lesson goes deeper into, for sub-lessons.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expanded_from: Option<StepRef>,
+    /// Key terms of the lesson, extracted once every step has been generated.
+    #[serde(default)]
+    pub glossary: Vec<GlossaryEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub glossary_model: Option<String>,
+    /// What a student should be able to do after the lesson.
+    #[serde(default)]
+    pub objectives: Vec<String>,
+    /// Concepts the lesson assumes the student already knows.
+    #[serde(default)]
+    pub prerequisites: Vec<String>,
+    /// Estimated seconds to go through every step, see `duration::lesson_seconds`.
+    #[serde(default)]
+    pub duration: u32,
+    /// Who the lesson was written for, when more was given than `difficulty`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<Audience>,
+    /// `audience` as shown to people browsing the museum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience_label: Option<String>,
+    /// The most severe moderation verdict on the lesson's content, if any was flagged
+    /// or blocked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moderation: Option<ModerationVerdict>,
+    /// Hidden by a moderator from the museum gallery.
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+impl LessonRecord {
+    pub fn new(prompt: String, difficulty: Difficulty, user: Option<String>) -> Self {
+        LessonRecord {
+            prompt,
+            difficulty,
+            user,
+            created_at: Some(DateTime::now()),
+            status: LessonStatus::Generating,
+            ..Default::default()
+        }
+    }
+
+    /// Sets who the lesson is for, along with the label the museum shows.
+    pub fn with_audience(mut self, audience: Option<Audience>) -> Self {
+        self.audience_label = audience.as_ref().map(Audience::describe).filter(|label| !label.is_empty());
+        self.audience = audience;
+        self
+    }
+
+    /// Level description used in prompts: the difficulty, refined by the audience.
+    pub fn audience_prompt(&self) -> String {
+        let level = String::from(self.difficulty.clone());
+        match &self.audience_label {
+            Some(label) => format!("{} para {}", level, label),
+            None => level,
+        }
+    }
+
+    /// Whether `user` requested the lesson. Lessons requested anonymously have no
+    /// owner, so nobody can change them.
+    pub fn is_owned_by(&self, user: Option<&str>) -> bool {
+        user.is_some() && self.user.as_deref() == user
+    }
+}
+
+/// A stored record with fields that stay in the database and are never sent to
+/// clients. User ids double as credentials, so they are always private.
+pub trait Private: Serialize {
+    const PRIVATE_FIELDS: &'static [&'static str];
+}
+
+/// `T` as sent to clients, without its `PRIVATE_FIELDS`.
+pub struct Public<'a, T: Private>(pub &'a T);
+
+impl<T: Private> Serialize for Public<'_, T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut value = serde_json::to_value(self.0).map_err(serde::ser::Error::custom)?;
+        if let Some(fields) = value.as_object_mut() {
+            for field in T::PRIVATE_FIELDS {
+                fields.remove(*field);
+            }
+        }
+        value.serialize(serializer)
+    }
+}
+
+/// The owner, their address hash and the per-stage cost breakdown.
+impl Private for LessonRecord {
+    const PRIVATE_FIELDS: &'static [&'static str] = &["user", "ip_hash", "usage"];
+}
+
+/// The editor, who is the lesson's owner.
+impl Private for Revision {
+    const PRIVATE_FIELDS: &'static [&'static str] = &["author"];
+}
+
+/// Hand-editable content of a lesson (`title`, `description`) or of one of its steps
+/// (`title`, `explanation`, `speech`, `references`). Used as the body of edit requests
+/// and to record what each revision changed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ContentEdit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speech: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub references: Option<Vec<String>>,
+}
+
+/// One manual edit of a lesson or step, with the previous and new value of every
+/// field it changed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Revision {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub lesson: ObjectId,
+    /// Index of the edited step, `None` for edits to the lesson itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<u32>,
+    pub author: Option<String>,
+    pub created_at: DateTime,
+    /// The revision this one undid, for revisions created by a rollback.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollback_of: Option<ObjectId>,
+    pub before: ContentEdit,
+    pub after: ContentEdit,
+}
+
+fn serialize_difficulty<S>(difficulty: &Difficulty, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    serializer.serialize_i32(difficulty.clone() as i32)
 }
 
 fn deserialize_difficulty<'de, D>(deserializer: D) -> Result<Difficulty, D::Error>
@@ -47,6 +494,360 @@ fn deserialize_difficulty<'de, D>(deserializer: D) -> Result<Difficulty, D::Erro
     }
 }
 
+fn deserialize_optional_difficulty<'de, D>(deserializer: D) -> Result<Option<Difficulty>, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_difficulty")] Difficulty);
+
+    let value: Option<Wrapper> = Deserialize::deserialize(deserializer)?;
+    Ok(value.map(|Wrapper(difficulty)| difficulty))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    #[default]
+    Allow,
+    /// Kept, but marked for a moderator to look at.
+    Flag,
+    Block,
+}
+
+/// What moderation decided about a piece of text and why.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ModerationVerdict {
+    pub action: ModerationAction,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// `blocklist`, or the model that classified the text.
+    pub source: String,
+}
+
+/// A flagged or blocked piece of text, kept for moderators.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ModerationRecord {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// The lesson the text belongs to; `None` for prompts rejected before a lesson
+    /// was created.
+    #[serde(default)]
+    pub lesson: Option<ObjectId>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+    /// What was screened: `prompt`, `outline`, `step`, `lesson` (an edit of its title or
+    /// description), `image` (a Wikipedia file name), or a chat `question` or `answer`.
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<u32>,
+    /// The start of the screened text.
+    pub excerpt: String,
+    pub verdict: ModerationVerdict,
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportReason {
+    WrongFacts,
+    InappropriateImage,
+    InappropriateText,
+    Other,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    #[default]
+    Open,
+    /// A moderator acted on the lesson.
+    Resolved,
+    /// A moderator found nothing wrong.
+    Dismissed,
+}
+
+/// A user's complaint about a lesson or one of its steps.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Report {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub lesson: ObjectId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<u32>,
+    pub reason: ReportReason,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+    #[serde(default)]
+    pub status: ReportStatus,
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<DateTime>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NewReport {
+    #[serde(default)]
+    pub step: Option<u32>,
+    pub reason: ReportReason,
+    #[serde(default)]
+    pub details: Option<String>,
+}
+
+/// A user id or address hash that may no longer create or report content.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Ban {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: DateTime,
+}
+
+/// Bans a user id, an address hash, or both. With `lesson`, whoever requested that
+/// lesson is banned by id and address.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct NewBan {
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub ip_hash: Option<String>,
+    #[serde(default)]
+    pub lesson: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// A review card built from one step of a lesson.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Flashcard {
+    pub front: String,
+    pub back: String,
+    /// Index of the step the card comes from.
+    pub step: u32,
+    /// Id of the step's image in the images collection, for image steps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+/// The flashcards generated for a lesson, stored so they are only generated once.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Deck {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub lesson: ObjectId,
+    pub cards: Vec<Flashcard>,
+    pub model: String,
+    pub created_at: DateTime,
+}
+
+/// One step of a lesson in a user's review queue, scheduled with SM-2.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReviewItem {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user: String,
+    pub lesson: ObjectId,
+    pub step: u32,
+    /// The step title.
+    pub front: String,
+    /// The step explanation.
+    pub back: String,
+    pub ease: f64,
+    /// Days until the next review after the last successful one.
+    pub interval: u32,
+    /// Successful reviews in a row.
+    pub repetitions: u32,
+    /// Times the item was forgotten after having been learned.
+    pub lapses: u32,
+    pub reviews: u32,
+    pub due: DateTime,
+    #[serde(default)]
+    pub last_reviewed: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+/// How well a review item was remembered, from 0 (blackout) to 5 (perfect recall).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Grade {
+    pub grade: u8,
+}
+
+/// A quiz the client gave about a lesson and how the user did.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct QuizResult {
+    /// Step the quiz was about, or none for the whole lesson.
+    #[serde(default)]
+    pub step: Option<u32>,
+    pub score: u32,
+    pub total: u32,
+    #[serde(default = "DateTime::now")]
+    pub created_at: DateTime,
+}
+
+/// How far one user got through one lesson, merged across their devices.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserProgress {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user: String,
+    pub lesson: ObjectId,
+    /// Step the user was last on, to resume from.
+    #[serde(default)]
+    pub current_step: u32,
+    /// Steps the user has opened.
+    #[serde(default)]
+    pub viewed: Vec<u32>,
+    /// Steps whose audio the user listened to the end.
+    #[serde(default)]
+    pub listened: Vec<u32>,
+    /// Seconds spent on the lesson.
+    #[serde(default)]
+    pub time_spent: u64,
+    #[serde(default)]
+    pub quizzes: Vec<QuizResult>,
+    #[serde(default)]
+    pub completed: bool,
+    #[serde(default)]
+    pub completed_at: Option<DateTime>,
+    pub updated_at: DateTime,
+}
+
+/// What happened since the client last reported progress. Updates are added to the
+/// stored progress rather than replacing it, so devices do not overwrite each other.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ProgressUpdate {
+    /// Step the user is on now; it also counts as viewed.
+    #[serde(default)]
+    pub step: Option<u32>,
+    /// Seconds spent since the last update.
+    #[serde(default)]
+    pub seconds: Option<u32>,
+    /// Step whose audio finished playing.
+    #[serde(default)]
+    pub listened: Option<u32>,
+    #[serde(default)]
+    pub quiz: Option<QuizResult>,
+    /// Marks the lesson as finished even if some steps were skipped.
+    #[serde(default)]
+    pub completed: bool,
+}
+
+/// Payload of the `update_progress` socket event. Like `SocketQuestion`, the user is
+/// taken from the socket handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SocketProgress {
+    pub id: String,
+    #[serde(flatten)]
+    pub update: ProgressUpdate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+    /// Step the question was asked about, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<u32>,
+    /// Model that wrote an assistant message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub created_at: DateTime,
+}
+
+/// Questions one user asked about one lesson and the answers they got.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Conversation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub lesson: ObjectId,
+    pub user: String,
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
+    pub updated_at: DateTime,
+}
+
+/// A question about a lesson, optionally about one of its steps.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Question {
+    pub question: String,
+    #[serde(default)]
+    pub step: Option<u32>,
+}
+
+/// Payload of the `ask_question` socket event. The asker is taken from the socket
+/// handshake, never from the payload.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SocketQuestion {
+    pub id: String,
+    #[serde(flatten)]
+    pub question: Question,
+}
+
+/// A teacher's class. Students join with `code`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Classroom {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    /// User id of the teacher who owns the class.
+    pub teacher: String,
+    pub code: String,
+    #[serde(default)]
+    pub students: Vec<String>,
+    #[serde(default)]
+    pub assignments: Vec<Assignment>,
+    pub created_at: DateTime,
+}
+
+/// A lesson a teacher assigned to a class.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Assignment {
+    pub lesson: ObjectId,
+    #[serde(default)]
+    pub due: Option<DateTime>,
+    pub assigned_at: DateTime,
+}
+
+/// Body for creating a classroom.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NewClassroom {
+    pub name: String,
+}
+
+/// Body for joining a classroom.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JoinClassroom {
+    pub code: String,
+}
+
+/// Body for assigning a lesson, with an optional RFC 3339 due date.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NewAssignment {
+    pub lesson: String,
+    #[serde(default)]
+    pub due: Option<String>,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct User {
     pub email: String,
@@ -64,6 +865,13 @@ pub enum StatusCodes {
     UserNotFound = 5,
     LessonNotFound = 6,
     AudioNotFound = 7,
+    NotGenerating = 8,
+    Unauthorized = 9,
+    QuotaExceeded = 10,
+    RateLimited = 11,
+    InvalidState = 12,
+    Rejected = 13,
+    Banned = 14,
 }
 
 impl Serialize for StatusCodes {
@@ -72,16 +880,11 @@ impl Serialize for StatusCodes {
     }
 }
 
-// pub struct Step {
-//     pub title: String,
-//     pub explanation: String,
-//     pub image: Option<String>,
-//     pub tts: String,
-// }
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Image {
     pub data: String,
 }
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TTS {
     pub data: Vec<u8>,
@@ -97,4 +900,111 @@ pub enum WebSocketEvents {
     RequestLessonData,
     #[strum(serialize = "update_lesson_data")]
     UpdateLessonData,
+    #[strum(serialize = "lesson_progress")]
+    LessonProgress,
+    #[strum(serialize = "cancel_lesson")]
+    CancelLesson,
+    #[strum(serialize = "ask_question")]
+    AskQuestion,
+    #[strum(serialize = "question_token")]
+    QuestionToken,
+    #[strum(serialize = "step_partial")]
+    StepPartial,
+    #[strum(serialize = "update_progress")]
+    UpdateProgress,
+    #[strum(serialize = "watch_progress")]
+    WatchProgress,
+    #[strum(serialize = "progress_updated")]
+    ProgressUpdated,
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson;
+    use super::*;
+
+    /// A lesson with an image step and a step that was not generated, which is all
+    /// the round trip tests look at. Tests that need more set it themselves.
+    fn sample_lesson() -> LessonRecord {
+        let mut lesson = LessonRecord::new("La fotosíntesis".to_string(), Difficulty::University, None);
+        lesson.id = Some(ObjectId::new());
+        lesson.steps = vec![
+            Some(Step {
+                title: "Cloroplastos".to_string(),
+                media_type: MediaType::Image,
+                image: Some(ObjectId::new().to_string()),
+                explanation: "Orgánulo verde".to_string(),
+                ..Default::default()
+            }),
+            None
+        ];
+        lesson
+    }
+
+    #[test]
+    fn lesson_round_trips_through_bson() {
+        let lesson = sample_lesson();
+        let document = bson::to_document(&lesson).unwrap();
+        assert_eq!(document.get_i32("difficulty").unwrap(), 2);
+        assert_eq!(bson::from_document::<LessonRecord>(document).unwrap(), lesson);
+    }
+
+    #[test]
+    fn lesson_round_trips_through_json() {
+        let lesson = sample_lesson();
+        let json = serde_json::to_value(&lesson).unwrap();
+        assert_eq!(json["steps"][0]["media_type"], "image");
+        assert!(json["steps"][1].is_null());
+        assert_eq!(serde_json::from_value::<LessonRecord>(json).unwrap(), lesson);
+    }
+
+    #[test]
+    fn public_lesson_hides_private_fields() {
+        let mut lesson = sample_lesson();
+        lesson.user = Some("user-1".to_string());
+        lesson.ip_hash = Some("3f1a".to_string());
+        lesson.usage = BTreeMap::from([("outline".to_string(), TokenUsage { requests: 1, ..Default::default() })]);
+        let json = serde_json::to_value(Public(&lesson)).unwrap();
+        for field in LessonRecord::PRIVATE_FIELDS {
+            assert!(json.get(field).is_none(), "{} was published", field);
+        }
+        assert_eq!(json["title"], lesson.title);
+    }
+
+    #[test]
+    fn lessons_without_owner_are_read_only() {
+        let mut lesson = sample_lesson();
+        assert!(!lesson.is_owned_by(None));
+        assert!(!lesson.is_owned_by(Some("user-1")));
+        lesson.user = Some("user-1".to_string());
+        assert!(lesson.is_owned_by(Some("user-1")));
+        assert!(!lesson.is_owned_by(Some("user-2")));
+        assert!(!lesson.is_owned_by(None));
+    }
+
+    #[test]
+    fn new_lesson_has_no_generated_fields() {
+        let lesson = LessonRecord::new("Volcanes".to_string(), Difficulty::Elementary, None);
+        let document = bson::to_document(&lesson).unwrap();
+        assert!(!document.contains_key("_id"));
+        assert!(document.get_array("steps").unwrap().is_empty());
+        let lesson: LessonRecord = bson::from_document(document).unwrap();
+        assert_eq!(lesson.difficulty, Difficulty::Elementary);
+    }
+
+    #[test]
+    fn legacy_steps_fill_missing_fields() {
+        let document =
+            doc! {
+            "title": "Paso",
+            "image": null,
+            "explanation": "Texto",
+            "speech": "Voz",
+            "tts": "",
+            "references": [],
+        };
+        let step: Step = bson::from_document(document).unwrap();
+        assert_eq!(step.media_type, MediaType::Text);
+        assert!(step.models.is_empty());
+    }
 }
@@ -1,28 +1,75 @@
-use base64::{ encode, engine::{ self, general_purpose, GeneralPurpose }, Engine };
-use futures::StreamExt;
+use base64::{ engine::general_purpose, Engine };
+use futures::{ stream, StreamExt };
 use mongodb::{
-    bson::{ doc, oid::ObjectId, Document },
+    bson::{ self, doc, oid::ObjectId, Bson, Document },
     change_stream::event::OperationType,
     options::{ FullDocumentBeforeChangeType, FullDocumentType },
     Client,
     Collection,
 };
-use openrouter_api::{ api::ChatApi, utils, ChatCompletionRequest, Message, OpenRouterClient };
-use reqwest::header;
 use serde_json::json;
 use socketioxide::SocketIo;
-use tokio::task;
+use tokio::{ sync::{ mpsc, Mutex }, task };
+use tokio_util::sync::CancellationToken;
 use tracing::info;
-use std::env;
-use crate::types::{ Difficulty, Image, WebSocketEvents, TTS };
-use mongodb::bson::Binary;
-use mongodb::bson::spec::BinarySubtype;
+use std::{ collections::{ BTreeMap, HashMap, HashSet }, env };
+use crate::{
+    config::env_number,
+    duration,
+    http::HTTP,
+    llm::{ Completion, Llm, Stage },
+    structured::{ self, Explanation, Glossary, Outline, References, WikipediaReference },
+    jobs,
+    moderation::{ Subject, MODERATION },
+    quota::QUOTAS,
+    readability,
+    review,
+    types::{
+        Ban,
+        Classroom,
+        Conversation,
+        Deck,
+        Difficulty,
+        GlossaryEntry,
+        Image,
+        LessonRecord,
+        LessonStatus,
+        MediaType,
+        ModerationAction,
+        ModerationRecord,
+        ModerationVerdict,
+        OutlineItem,
+        Public,
+        Readability,
+        Report,
+        ReviewItem,
+        Revision,
+        Step,
+        StepRef,
+        TokenUsage,
+        UserProgress,
+        WebSocketEvents,
+        TTS,
+        UsageRecord,
+    },
+    websocket,
+};
 
 #[derive(Debug, Clone)]
 pub struct Collections {
-    pub lessons: Collection<Document>,
+    pub lessons: Collection<LessonRecord>,
     pub images: Collection<Image>,
     pub tts: Collection<TTS>,
+    pub usage: Collection<UsageRecord>,
+    pub revisions: Collection<Revision>,
+    pub conversations: Collection<Conversation>,
+    pub decks: Collection<Deck>,
+    pub reviews: Collection<ReviewItem>,
+    pub progress: Collection<UserProgress>,
+    pub classrooms: Collection<Classroom>,
+    pub moderation: Collection<ModerationRecord>,
+    pub reports: Collection<Report>,
+    pub bans: Collection<Ban>,
 }
 
 pub async fn init_database(io: &SocketIo) -> Result<Collections, String> {
@@ -39,6 +86,28 @@ pub async fn init_database(io: &SocketIo) -> Result<Collections, String> {
         ),
         images: db.collection(&env::var("IMAGE_COLLECTION").expect("IMAGE_COLLECTION must be set")),
         tts: db.collection(&env::var("TTS_COLLECTION").expect("TTS_COLLECTION must be set")),
+        usage: db.collection(&env::var("USAGE_COLLECTION").unwrap_or_else(|_| "usage".to_string())),
+        revisions: db.collection(
+            &env::var("REVISIONS_COLLECTION").unwrap_or_else(|_| "revisions".to_string())
+        ),
+        conversations: db.collection(
+            &env::var("CONVERSATIONS_COLLECTION").unwrap_or_else(|_| "conversations".to_string())
+        ),
+        decks: db.collection(&env::var("DECKS_COLLECTION").unwrap_or_else(|_| "decks".to_string())),
+        reviews: db.collection(
+            &env::var("REVIEWS_COLLECTION").unwrap_or_else(|_| "reviews".to_string())
+        ),
+        progress: db.collection(
+            &env::var("PROGRESS_COLLECTION").unwrap_or_else(|_| "progress".to_string())
+        ),
+        classrooms: db.collection(
+            &env::var("CLASSROOMS_COLLECTION").unwrap_or_else(|_| "classrooms".to_string())
+        ),
+        moderation: db.collection(
+            &env::var("MODERATION_COLLECTION").unwrap_or_else(|_| "moderation".to_string())
+        ),
+        reports: db.collection(&env::var("REPORTS_COLLECTION").unwrap_or_else(|_| "reports".to_string())),
+        bans: db.collection(&env::var("BANS_COLLECTION").unwrap_or_else(|_| "bans".to_string())),
     };
 
     let io = io.clone();
@@ -67,12 +136,12 @@ pub async fn init_database(io: &SocketIo) -> Result<Collections, String> {
             let Some(change) = change_stream.next().await.transpose().expect("Failed to get change")
         {
             if change.operation_type == OperationType::Update {
-                let updated_doc = change.full_document
+                let updated_lesson = change.full_document
                     .as_ref()
                     .expect("Failed to get full document");
-                let oid = updated_doc.get_object_id("_id").unwrap().to_string();
+                let oid = updated_lesson.id.expect("Lesson has no _id").to_string();
                 info!("Lesson updated: {}", oid);
-                let _ = io.to(oid).emit(WebSocketEvents::UpdateLessonData, &updated_doc.clone());
+                io.to(oid).emit(WebSocketEvents::UpdateLessonData, &Public(updated_lesson)).await.ok();
             }
         }
     });
@@ -81,541 +150,1056 @@ pub async fn init_database(io: &SocketIo) -> Result<Collections, String> {
     Ok(collections)
 }
 
+/// Which part of the pipeline to run for a lesson.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineRun {
+    /// Outline followed by every step.
+    Full,
+    /// Outline only, leaving the lesson in review until `StepsOnly` is started.
+    OutlineOnly,
+    /// Steps for the outline already stored on the lesson.
+    StepsOnly,
+}
+
+/// Why a pipeline run stopped before finishing.
+#[derive(Debug)]
+enum PipelineError {
+    Failed(String),
+    /// Moderation blocked generated content; the verdict is already on the lesson.
+    Rejected(ModerationVerdict),
+}
+
+impl From<String> for PipelineError {
+    fn from(e: String) -> Self {
+        PipelineError::Failed(e)
+    }
+}
+
 //functions for pipeline
-pub async fn start_lesson_pipeline(
-    prompt: String,
-    difficulty: Difficulty,
-    id: String,
-    collections: Collections
+/// Runs the pipeline for a stored lesson in the background. The job is registered
+/// before this returns, so the lesson can be cancelled as soon as its id is known.
+pub fn spawn_lesson_pipeline(lesson: LessonRecord, collections: Collections, run: PipelineRun) {
+    let id = lesson.id.expect("Lesson must be stored before generating it").to_string();
+    let token = jobs::register(&id);
+    task::spawn(start_lesson_pipeline(lesson, collections, run, token));
+}
+
+async fn start_lesson_pipeline(
+    lesson: LessonRecord,
+    collections: Collections,
+    run: PipelineRun,
+    token: CancellationToken
 ) {
+    let id = lesson.id.expect("Lesson must be stored before generating it").to_string();
     info!("Starting lesson pipeline for id: {}", id);
 
-    let api_key = match utils::load_api_key_from_env() {
-        Ok(key) => key,
+    let llm = match Llm::from_env() {
+        Ok(llm) => llm.with_token_budget(QUOTAS.max_tokens_per_lesson).with_usage(lesson.usage.clone()),
         Err(e) => {
-            info!("Failed to load API key: {}", e);
+            info!("Failed to initialize LLM client: {}", e);
+            jobs::finish(&id);
             return;
         }
     };
 
-    let client = match
-        OpenRouterClient::new()
-            .with_base_url("https://openrouter.ai/api/v1/")
-            .and_then(|c| c.with_api_key(api_key.clone()))
-    {
-        Ok(c) => c,
-        Err(e) => {
-            info!("Failed to initialize client: {}", e);
-            return;
+    // Dropping the pipeline future on cancellation aborts any in-flight LLM or HTTP call;
+    // steps that were already stored are kept.
+    let status = tokio::select! {
+        _ = token.cancelled() => {
+            info!("Lesson pipeline cancelled for id: {}", id);
+            LessonStatus::Cancelled
         }
+        result = run_lesson_pipeline(&lesson, &id, &llm, &collections, run) => match result {
+            Ok(()) if run == PipelineRun::OutlineOnly => LessonStatus::Review,
+            Ok(()) => LessonStatus::Completed,
+            Err(PipelineError::Rejected(verdict)) => {
+                info!("Lesson pipeline for id {} rejected by moderation: {:?}", id, verdict.reason);
+                LessonStatus::Rejected
+            }
+            Err(PipelineError::Failed(e)) if llm.over_budget() => {
+                info!("Lesson pipeline for id {} ran out of tokens: {}", id, e);
+                LessonStatus::QuotaExceeded
+            }
+            Err(PipelineError::Failed(e)) => {
+                info!("Lesson pipeline failed for id {}: {}", id, e);
+                LessonStatus::Failed
+            }
+        },
     };
+    jobs::finish(&id);
+    record_usage(&id, lesson.user.as_deref(), &llm, &collections).await;
+
+    collections.lessons
+        .update_one(
+            doc! { "_id": ObjectId::parse_str(&id).unwrap() },
+            doc! { "$set": { "status": bson::to_bson(&status).unwrap() } }
+        ).await
+        .ok();
+
+    // The requesting user gets the finished lesson in their review queue
+    if status == LessonStatus::Completed && let Some(user) = &lesson.user {
+        let oid = ObjectId::parse_str(&id).unwrap();
+        if let Err(status) = review::enroll_lesson(user, oid, &collections).await {
+            info!("Failed to add lesson {} to the review queue: {:?}", id, status);
+        }
+    }
+}
+
+/// Adds the per-stage usage since the last call to the lesson and to the requesting
+/// user's daily rollup.
+pub async fn record_usage(id: &str, user: Option<&str>, llm: &Llm, collections: &Collections) {
+    let pending = llm.take_pending_stage_usage();
+    if pending.is_empty() {
+        return;
+    }
+    // Steps finish concurrently, so add to the stored counters instead of replacing them
+    let mut counters = Document::new();
+    let mut total = TokenUsage::default();
+    for (stage, usage) in &pending {
+        counters.insert(format!("usage.{}.requests", stage), usage.requests);
+        counters.insert(format!("usage.{}.prompt_tokens", stage), usage.prompt_tokens as i64);
+        counters.insert(format!("usage.{}.completion_tokens", stage), usage.completion_tokens as i64);
+        counters.insert(format!("usage.{}.cost", stage), usage.cost);
+        total.add(usage);
+    }
+    collections.lessons
+        .update_one(doc! { "_id": ObjectId::parse_str(id).unwrap() }, doc! { "$inc": counters }).await
+        .ok();
+    if let Err(e) = record_user_usage(user, &total, collections).await {
+        info!("Failed to record usage for lesson {}: {}", id, e);
+    }
+}
+
+/// Adds `usage` to the user's rollup for today.
+pub async fn record_user_usage(
+    user: Option<&str>,
+    usage: &TokenUsage,
+    collections: &Collections
+) -> mongodb::error::Result<()> {
+    let day = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    collections.usage
+        .update_one(
+            doc! { "user": user, "day": day },
+            doc! {
+                "$inc": {
+                    "requests": usage.requests,
+                    "prompt_tokens": usage.prompt_tokens as i64,
+                    "completion_tokens": usage.completion_tokens as i64,
+                    "cost": usage.cost,
+                }
+            }
+        )
+        .upsert(true).await
+        .map(|_| ())
+}
+
+async fn run_lesson_pipeline(
+    lesson: &LessonRecord,
+    id: &str,
+    llm: &Llm,
+    collections: &Collections,
+    run: PipelineRun
+) -> Result<(), PipelineError> {
+    match run {
+        PipelineRun::Full => {
+            let outline = generate_outline(lesson, id, llm, collections).await?;
+            generate_steps(lesson, &outline, id, llm, collections).await?;
+        }
+        PipelineRun::OutlineOnly => {
+            generate_outline(lesson, id, llm, collections).await?;
+            return Ok(());
+        }
+        PipelineRun::StepsOnly => generate_steps(lesson, &lesson.outline, id, llm, collections).await?,
+    }
+    store_duration(id, collections).await?;
+    // The lesson is usable without a glossary, so failing to build one is not fatal
+    if let Err(e) = generate_glossary(lesson, id, llm, collections).await {
+        info!("Failed to generate glossary for lesson {}: {}", id, e);
+    }
+    Ok(())
+}
+
+/// Generates and stores the lesson's title, description and outline.
+async fn generate_outline(
+    lesson: &LessonRecord,
+    id: &str,
+    llm: &Llm,
+    collections: &Collections
+) -> Result<Vec<OutlineItem>, PipelineError> {
+    let prompt = &lesson.prompt;
 
-    let outline_prompt: String = format!(
+    let mut outline_prompt: String = format!(
         "Dado el tema '{}', crea un esquema para explicarlo a un nivel {}. \
         Devuelve un objeto JSON con: \
         - 'title': título general de la lección \
         - 'description': descripción breve de la lección \
+        - 'objectives': objetivos de aprendizaje, frases cortas que empiezan con un verbo (máximo 8) \
+        - 'prerequisites': conceptos que el estudiante ya debe conocer para seguir la lección, vacío si no hace falta ninguno (máximo 8) \
         - 'outline': array de objetos, cada uno con 'title' (título del paso), 'media_type' (media que va a generar, los opciones son ['text', 'image'], 'prompt' (instrucción para explicar el paso o generar el imagen), y 'speech' es para generar un texto que dura 10 segundos dando una explicacion sobr el imagen o texto. \
         Si vas a poner un imagen, el 'prompt' debe ser una pregunta o instrucción que se puede responder con una imagen y si incluye texto, debe estar claro en el prompt que texto debe poner o especificar que no va a haber texto. \
         La información debe adaptarse al nivel educativo: primaria con pasos simples, universitario con pasos detallados. Todos deben tener un balance entre imagenes y texto. \
         Evita redundancias. Texto en español sin formato. Solo JSON sin otros textos.",
         prompt,
-        Into::<String>::into(difficulty)
+        lesson.audience_prompt()
     );
+    if let Some(source) = load_remix_source(lesson, collections).await {
+        outline_prompt.push_str(&remix_context(&source, lesson.remix_instructions.as_deref()));
+    }
+    if let Some(parent) = lesson.expanded_from {
+        outline_prompt.push_str(&expand_context(parent, collections).await);
+    }
 
-    let request = ChatCompletionRequest {
-        model: "google/gemini-2.0-flash-lite-001".to_string(),
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: outline_prompt,
-            name: None,
-            tool_calls: None,
-        }],
-        stream: None,
-        response_format: serde_json
-            ::from_value(
-                json!({
-            "type": "json_schema",
-            "json_schema": {
-                "name": "outline",
-                "strict": true,
-                "schema": {
-                    "type": "object",
-                    "properties": {
-                        "title": {"type": "string"},
-                        "description": {"type": "string"},
-                        "outline": {
-                            "type": "array",
-                            "items": {
-                                "type": "object",
-                                "properties": {
-                                    "title": {"type": "string"},
-                                    "media_type": {"type": "string"},
-                                    "prompt": {"type": "string"},
-                                    "speech": {"type": "string"}
-                                },
-                                "required": ["title", "media_type", "prompt", "speech"],
-                                "additionalProperties": false
-                            }
-                        }
-                    },
-                    "required": ["title", "description", "outline"],
-                    "additionalProperties": false
-                }
-            }
-        })
-            )
-            .ok(),
-        tools: None,
-        provider: None,
-        models: None,
-        transforms: None,
-    };
-
-    let chat_api = match client.chat() {
-        Ok(api) => api,
+    let outline = match
+        llm.complete::<Outline>(
+            Stage::Outline,
+            vec![json!({ "role": "user", "content": outline_prompt })]
+        ).await
+    {
+        Ok(c) => c,
         Err(e) => {
-            info!("Failed to create chat API: {}", e);
-            return;
+            return Err(format!("Outline request failed: {}", e).into());
         }
     };
+    let Outline { title, description, objectives, prerequisites, outline: mut outline_steps } = outline.value;
+    if QUOTAS.max_steps_per_lesson > 0 {
+        outline_steps.truncate(QUOTAS.max_steps_per_lesson);
+    }
+    let outline_text: Vec<&str> = [title.as_str(), description.as_str()]
+        .into_iter()
+        .chain(objectives.iter().map(String::as_str))
+        .chain(outline_steps.iter().flat_map(|item| [item.title.as_str(), item.prompt.as_str(), item.speech.as_str()]))
+        .collect();
+    moderate(lesson, id, "outline", None, &outline_text.join("\n"), llm, collections).await?;
 
-    let response = match chat_api.chat_completion(request).await {
-        Ok(r) => r,
+    // Update lesson with metadata and outline
+    collections.lessons
+        .update_one(
+            doc! { "_id": ObjectId::parse_str(id).unwrap() },
+            doc! {
+                "$set": {
+                    "title": title,
+                    "description": description,
+                    "objectives": objectives,
+                    "prerequisites": prerequisites,
+                    "outline": bson::to_bson(&outline_steps).expect("Failed to serialize outline"),
+                    "outline_model": outline.model,
+                }
+            }
+        ).await
+        .map_err(|e| format!("Failed to update lesson with outline: {}", e))?;
+    record_usage(id, lesson.user.as_deref(), llm, collections).await;
+    Ok(outline_steps)
+}
+
+/// The lesson `lesson` was remixed from, if any.
+async fn load_remix_source(lesson: &LessonRecord, collections: &Collections) -> Option<LessonRecord> {
+    let source = lesson.remixed_from?;
+    match collections.lessons.find_one(doc! { "_id": source }).await {
+        Ok(source) => source,
         Err(e) => {
-            info!("Outline request failed: {}", e);
-            return;
+            info!("Failed to load remix source {}: {}", source, e);
+            None
         }
-    };
+    }
+}
 
-    let raw_content = &response.choices[0].message.content
-        .replace("```json", "")
-        .replace("```", "");
-    // info!("Raw outline response: {}", raw_content);
+/// Extra outline instructions for a remix: the source outline to adapt and whatever
+/// the user asked to change.
+fn remix_context(source: &LessonRecord, instructions: Option<&str>) -> String {
+    let outline: Vec<_> = source.outline
+        .iter()
+        .map(|item| json!({ "title": item.title, "media_type": item.media_type, "prompt": item.prompt }))
+        .collect();
+    let mut context = format!(
+        " Esta lección es una adaptación de la lección '{}'. Usa su esquema como punto de partida y adáptalo al nivel indicado. \
+        Conserva exactamente el mismo 'title' en los pasos que traten el mismo contenido. Esquema original: {}.",
+        source.title,
+        serde_json::Value::Array(outline)
+    );
+    if let Some(instructions) = instructions {
+        context.push_str(&format!(" Instrucciones adicionales del usuario: {}", instructions));
+    }
+    context
+}
 
-    let parsed_json: serde_json::Value = match serde_json::from_str(raw_content) {
-        Ok(v) => v,
-        Err(e) => {
-            info!("Failed to parse JSON outline: {}", e);
-            return;
-        }
+/// Extra outline instructions for a sub-lesson: what the parent lesson and step already
+/// cover, so the new lesson goes deeper instead of repeating them.
+async fn expand_context(parent: StepRef, collections: &Collections) -> String {
+    let Ok(Some(lesson)) = collections.lessons.find_one(doc! { "_id": parent.lesson }).await else {
+        return String::new();
+    };
+    let step = lesson.steps
+        .get(parent.step as usize)
+        .and_then(|s| s.as_ref());
+    let Some(step) = step else {
+        return String::new();
     };
+    let covered: Vec<&str> = lesson.outline
+        .iter()
+        .map(|item| item.title.as_str())
+        .collect();
+    format!(
+        " Esta lección profundiza en el paso '{}' de la lección '{}' ({}). \
+        El paso explicaba: {} \
+        Enfócate solo en este tema y ve más a fondo que el paso original; no repitas los demás pasos de la lección: {}.",
+        step.title,
+        lesson.title,
+        lesson.description,
+        step.explanation,
+        covered.join(", ")
+    )
+}
 
-    // Extract fields from parsed JSON
-    let title = parsed_json["title"].as_str().unwrap_or("").to_string();
-    let description = parsed_json["description"].as_str().unwrap_or("").to_string();
-    let default_outline = Vec::new();
-    let outline_array = parsed_json["outline"].as_array().unwrap_or(&default_outline);
+/// An image from the remix source that an image step with the same title can reuse
+/// instead of searching for a new one.
+struct ReusableImage {
+    title: String,
+    image: String,
+    references: Vec<String>,
+}
 
-    // Convert outline to BSON documents
-    let outline_bson: Vec<_> = outline_array
-        .iter()
+fn reusable_images(source: Option<LessonRecord>) -> Vec<ReusableImage> {
+    source
+        .map(|source| source.steps)
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .filter(|step| step.media_type == MediaType::Image)
         .filter_map(|step| {
-            Some(
-                doc! {
-            "title": step.get("title")?.as_str()?,
-            "media_type": step.get("media_type")?.as_str()?,
-            "prompt": step.get("prompt")?.as_str()?
-        }
-            )
+            Some(ReusableImage { title: normalize_title(&step.title), image: step.image?, references: step.references })
+        })
+        .collect()
+}
+
+fn normalize_title(title: &str) -> String {
+    title.trim().to_lowercase()
+}
+
+/// Generates every step of `outline`, storing each one in its slot as it finishes.
+async fn generate_steps(
+    lesson: &LessonRecord,
+    outline_steps: &[OutlineItem],
+    id: &str,
+    llm: &Llm,
+    collections: &Collections
+) -> Result<(), PipelineError> {
+    let prompt = &lesson.prompt;
+    collections.lessons
+        .update_one(
+            doc! { "_id": ObjectId::parse_str(id).unwrap() },
+            doc! { "$set": { "steps": vec![Bson::Null; outline_steps.len()] } }
+        ).await
+        .map_err(|e| format!("Failed to reset lesson steps: {}", e))?;
+
+    let wikipedia_url = get_wikipedia_reference(prompt, llm, collections, id).await;
+    let wikipedia_images = get_wikipedia_images(&wikipedia_url).await;
+    let reusable_images = reusable_images(load_remix_source(lesson, collections).await);
+    let level = lesson.audience_prompt();
+    let screened_images = Mutex::default();
+
+    let context = StepContext {
+        lesson,
+        id,
+        llm,
+        collections,
+        difficulty: &lesson.difficulty,
+        level: &level,
+        wikipedia_url: &wikipedia_url,
+        wikipedia_images: &wikipedia_images,
+        reusable_images: &reusable_images,
+        screened_images: &screened_images,
+    };
+    let total = outline_steps.len();
+    let mut completed = 0;
+
+    // Generate steps concurrently; each one is screened and stored by its own task so
+    // that a slow write does not hold back the steps still generating
+    let tasks: Vec<_> = outline_steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let context = &context;
+            async move { (i, produce_step(i, step, context).await) }
         })
         .collect();
+    let mut steps = stream::iter(tasks).buffer_unordered(step_concurrency());
+    while let Some((i, stored)) = steps.next().await {
+        completed += 1;
+        let stored = stored?;
+        websocket::emit_lesson_progress(id, i, completed, total, stored).await;
+        if !stored && llm.over_budget() {
+            return Err("Token budget exceeded while generating steps".to_string().into());
+        }
+    }
+    Ok(())
+}
 
-    // Update lesson with metadata and outline
+/// Generates the step at `i`, screens it and stores it in its outline slot. `Ok(false)`
+/// if the step could not be generated.
+async fn produce_step(i: usize, step: &OutlineItem, context: &StepContext<'_>) -> Result<bool, PipelineError> {
+    let StepContext { lesson, id, llm, collections, .. } = *context;
+    let Some(step) = generate_step(i, step, context).await? else {
+        return Ok(false);
+    };
+    // An image step's explanation describes the picture, but not always what a file
+    // name says, so the file name was screened on its own when the image was picked
+    let step_text = [step.title.as_str(), step.explanation.as_str(), step.speech.as_str()].join("\n");
+    moderate(lesson, id, "step", Some(i as u32), &step_text, llm, collections).await?;
     collections.lessons
         .update_one(
-            doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
+            doc! { "_id": ObjectId::parse_str(id).unwrap() },
             doc! {
                 "$set": {
-                    "title": title,
-                    "description": description,
-                    "outline": outline_bson
+                    format!("steps.{}", i): bson::to_bson(&step).expect("Failed to serialize step")
                 }
             }
         ).await
-        .expect("Failed to update lesson with outline");
+        .map_err(|e| format!("Failed to update lesson with new step: {}", e))?;
+    record_usage(id, lesson.user.as_deref(), llm, collections).await;
+    Ok(true)
+}
 
-    let wikipedia_url = get_wikipedia_reference(&prompt, &chat_api, &collections, &id).await;
-    let wikipedia_images = get_wikipedia_images(&wikipedia_url).await;
-    let client = reqwest::Client::new();
-
-    // Process each outline step
-    for (i, step) in outline_array.iter().enumerate() {
-        let step_title = step
-            .get("title")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        let step_prompt_content = step
-            .get("prompt")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        let media_type = step
-            .get("media_type")
-            .and_then(|v| v.as_str())
-            .unwrap_or("text");
-        let speech = step
-            .get("speech")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        info!("Wikipedia Images: {:?}", wikipedia_images);
-        let (image, explanation) = if media_type == "image" {
-            let mut res = None;
-            if let Some(images) = wikipedia_images.clone() {
-                for image in images {
-                    // if is_relevant_image(image.clone(), step_title) {
-                    if let Some(image_url) = get_image_url(&image).await {
-                        let explanation = generate_image_explanation(
-                            image_url.clone(),
-                            api_key.clone()
-                        ).await;
-                        info!("Image URL: {}", image_url);
-                        // download the image as base64 and then store it in MongoDB
-                        let image_response = match client.get(&image_url).send().await {
-                            Ok(r) => r,
-                            Err(e) => {
-                                info!("Image download failed: {}", e);
-                                continue;
-                            }
-                        };
-                        let image_data = match image_response.bytes().await {
-                            Ok(data) => data,
-                            Err(e) => {
-                                info!("Failed to read image data: {}", e);
-                                continue;
-                            }
-                        };
-
-                        // Encode image data to string
-                        let image_data = general_purpose::STANDARD.encode(&image_data);
-
-                        info!("First 50 chars of image data: {:?}", &image_data[0..50]);
-
-                        let image_b64 = format!(
-                            "data:image/{};base64,{}",
-                            image_url.rsplit(".").next().unwrap_or("png"),
-                            &image_data
-                        );
+/// Screens generated `text`. A flag is stored on the lesson unless it is already
+/// blocked and hides it from the museum until a moderator unhides it; a block also
+/// stops the pipeline.
+async fn moderate(
+    lesson: &LessonRecord,
+    id: &str,
+    target: &str,
+    step: Option<u32>,
+    text: &str,
+    llm: &Llm,
+    collections: &Collections
+) -> Result<(), PipelineError> {
+    let oid = ObjectId::parse_str(id).unwrap();
+    let subject = Subject { lesson: Some(oid), user: lesson.user.as_deref(), ip_hash: lesson.ip_hash.as_deref() };
+    let verdict = MODERATION.screen(subject, target, step, text, Some(llm), collections).await;
+    if verdict.action == ModerationAction::Allow {
+        return Ok(());
+    }
+    store_verdict(oid, &verdict, collections).await?;
+    match verdict.action {
+        ModerationAction::Block => Err(PipelineError::Rejected(verdict)),
+        _ => Ok(()),
+    }
+}
 
-                        let explanation = step_prompt_content.to_string();
-
-                        // upload image to MongoDB
-                        let image_doc = Image {
-                            data: image_b64.clone(),
-                        };
-                        let image_result = collections.images.insert_one(image_doc).await;
-                        if image_result.is_err() {
-                            info!(
-                                "Failed to upload image to MongoDB: {}",
-                                image_result.err().unwrap()
-                            );
-                            continue;
-                        }
-                        let image_id = image_result
-                            .unwrap()
-                            .inserted_id.as_object_id()
-                            .unwrap()
-                            .to_string();
-
-                        res = Some((Some(image_id), explanation));
-                        break;
-                    }
+/// Stores `verdict` on lesson `oid` unless it is already blocked, hiding the lesson.
+pub async fn store_verdict(oid: ObjectId, verdict: &ModerationVerdict, collections: &Collections) -> Result<(), String> {
+    collections.lessons
+        .update_one(
+            doc! { "_id": oid, "moderation.action": { "$ne": bson::to_bson(&ModerationAction::Block).unwrap() } },
+            doc! { "$set": { "moderation": bson::to_bson(verdict).unwrap(), "hidden": true } }
+        ).await
+        .map_err(|e| format!("Failed to store moderation verdict: {}", e))?;
+    Ok(())
+}
+
+/// Whether Wikipedia image file name `image` may be used for step `i`. Each name is
+/// screened once per lesson, whichever step considers it first. A blocked name only
+/// rules out that image; a flag is stored on the lesson as for generated text.
+async fn image_allowed(image: &str, i: usize, context: &StepContext<'_>) -> Result<bool, PipelineError> {
+    let StepContext { lesson, id, llm, collections, screened_images, .. } = *context;
+    // Held while screening so steps considering the same name wait for one verdict
+    let mut screened = screened_images.lock().await;
+    if let Some(allowed) = screened.get(image) {
+        return Ok(*allowed);
+    }
+    let oid = ObjectId::parse_str(id).unwrap();
+    let subject = Subject { lesson: Some(oid), user: lesson.user.as_deref(), ip_hash: lesson.ip_hash.as_deref() };
+    let verdict = MODERATION.screen(subject, "image", Some(i as u32), image, Some(llm), collections).await;
+    if verdict.action == ModerationAction::Flag {
+        store_verdict(oid, &verdict, collections).await?;
+    }
+    let allowed = verdict.action != ModerationAction::Block;
+    screened.insert(image.to_string(), allowed);
+    Ok(allowed)
+}
+
+/// Stores the estimated time for the lesson's generated steps.
+pub async fn store_duration(id: &str, collections: &Collections) -> Result<(), String> {
+    let oid = ObjectId::parse_str(id).unwrap();
+    let stored = collections.lessons
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|e| format!("Failed to load lesson steps: {}", e))?
+        .ok_or_else(|| "Lesson no longer exists".to_string())?;
+    collections.lessons
+        .update_one(
+            doc! { "_id": oid },
+            doc! { "$set": { "duration": duration::lesson_seconds(&stored.steps) } }
+        ).await
+        .map_err(|e| format!("Failed to update lesson duration: {}", e))?;
+    Ok(())
+}
+
+/// Extracts the key terms of the generated steps, defined at the lesson's level, and
+/// stores them as the lesson's glossary. Each term is linked to the first step that
+/// mentions it, falling back to the step the model picked for terms that only appear
+/// inflected.
+async fn generate_glossary(
+    lesson: &LessonRecord,
+    id: &str,
+    llm: &Llm,
+    collections: &Collections
+) -> Result<(), String> {
+    let oid = ObjectId::parse_str(id).unwrap();
+    let stored = collections.lessons
+        .find_one(doc! { "_id": oid }).await
+        .map_err(|e| format!("Failed to load lesson steps: {}", e))?
+        .ok_or_else(|| "Lesson no longer exists".to_string())?;
+    let steps: Vec<(usize, &Step)> = stored.steps
+        .iter()
+        .enumerate()
+        .filter_map(|(i, step)| step.as_ref().map(|step| (i, step)))
+        .collect();
+    if steps.is_empty() {
+        return Ok(());
+    }
+    let content: Vec<String> = steps
+        .iter()
+        .map(|(i, step)| format!("[{}] {}: {}", i, step.title, step.explanation))
+        .collect();
+    let prompt = format!(
+        "Extrae los términos clave de la siguiente lección sobre '{}' para un glosario, con una definición breve de cada uno adaptada a un nivel {}. \
+        Devuelve un objeto JSON con un arreglo 'terms'; cada elemento tiene 'term' (el término tal como aparece en el texto), \
+        'definition' (una o dos frases) y 'step' (el número entre corchetes del primer paso donde aparece). \
+        Máximo 40 términos, sin palabras comunes. Texto en español. Para mostrar matemáticas, usa KaTeX entre $. Solo JSON sin otros textos.\n\n{}",
+        stored.title,
+        lesson.audience_prompt(),
+        content.join("\n")
+    );
+    let completion = llm
+        .complete::<Glossary>(Stage::Glossary, vec![json!({ "role": "user", "content": prompt })]).await
+        .map_err(|e| format!("Glossary request failed: {}", e))?;
+
+    let mut seen = HashSet::new();
+    let mut glossary: Vec<GlossaryEntry> = completion.value.terms
+        .into_iter()
+        .filter_map(|mut entry| {
+            entry.term = entry.term.trim().to_string();
+            if !seen.insert(fold_text(&entry.term)) {
+                return None;
+            }
+            let suggested = steps.iter().any(|(i, _)| *i == (entry.step as usize));
+            entry.step = first_mention(&entry.term, &steps).or(suggested.then_some(entry.step))?;
+            Some(entry)
+        })
+        .collect();
+    glossary.sort_by_key(|entry| entry.step);
+    collections.lessons
+        .update_one(
+            doc! { "_id": oid },
+            doc! {
+                "$set": {
+                    "glossary": bson::to_bson(&glossary).expect("Failed to serialize glossary"),
+                    "glossary_model": completion.model,
                 }
-                // }
             }
-            // Get OpenAI API key
-            // let openai_key = match std::env::var("OPENAI_API_KEY") {
-            //     Ok(k) => k,
-            //     Err(_) => {
-            //         info!("Missing OPENAI_API_KEY environment variable");
-            //         continue;
-            //     }
-            // };
-
-            // push a value to the steps array at the position i
-            // collections.lessons
-            //     .update_one(
-            //         doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
-            //         doc! {
-            //             "$push": {
-            //                 "steps": {
-            //                     "$each": [{
-            //                         "title": step_title,
-            //                         "explanation": step_prompt_content,
-            //                         "image": Option::<String>::None,
-            //                         "tts": Option::<String>::None,
-            //                         "references": Array::new(),
-            //                     }],
-            //                     "$position": i as i32
-            //                 }
-            //             }
-            //         }
-            //     ).await
-            //     .expect("Failed to update lesson with new step");
-
-            // Create HTTP client
-
-            // let image_response = match
-            //     client
-            //         .post("https://api.openai.com/v1/images/generations")
-            //         .header(header::AUTHORIZATION, format!("Bearer {}", openai_key))
-            //         .json(
-            //             &json!({
-            //         "model": "gpt-image-1",
-            //         "prompt": format!("Dada la siguiente explicación, genera una imagen que represente visualmente el contenido de forma clara y coherente. Asegúrate de que la imagen esté relacionada directamente con el tema descrito. Explicación: {}", step_prompt_content),
-            //         "n": 1,
-            //         "size": "1024x1024",
-            //         "quality": "low",
-            //     })
-            //         )
-            //         .send().await
-            // {
-            //     Ok(r) => r,
-            //     Err(e) => {
-            //         info!("File generation failed: {}", e);
-            //         continue;
-            //     }
-            // };
-
-            // // Parse image response
-            // // info!("File response: {:?}", image_response);
-            // let image_json: serde_json::Value = match image_response.json().await {
-            //     Ok(j) => j,
-            //     Err(e) => {
-            //         info!("Failed to parse image response: {}", e);
-            //         continue;
-            //     }
-            // };
-
-            // let mut image_b64 = match image_json["data"][0]["b64_json"].as_str() {
-            //     Some(b64) => b64.to_string(),
-            //     None => {
-            //         info!("Missing base64 image data");
-            //         continue;
-            //     }
-            // };
-
-            // use reqwest to downloa the image
-
-            // image_b64 = "data:image/png;base64,".to_string() + &image_b64;
-            // // Generate explanation using original model
-
-            // // let explanation = generate_image_explanation(image_b64.clone(), api_key.clone()).await;
-            // let explanation = step_prompt_content.to_string();
-
-            // // upload image to MongoDB
-            // let image_doc = Image {
-            //     data: image_b64.clone(),
-            // };
-            // let image_result = collections.images.insert_one(image_doc).await;
-            // if image_result.is_err() {
-            //     info!("Failed to upload image to MongoDB: {}", image_result.err().unwrap());
-            //     continue;
-            // }
-            // let image_id = image_result.unwrap().inserted_id.as_object_id().unwrap().to_string();
+        ).await
+        .map_err(|e| format!("Failed to update lesson with glossary: {}", e))?;
+    record_usage(id, lesson.user.as_deref(), llm, collections).await;
+    Ok(())
+}
 
-            // (Some(image_id), explanation)
-            res.unwrap_or((None, speech.to_string()))
-        } else {
-            // Text-based step
-            let text_request = ChatCompletionRequest {
-                model: "google/gemini-2.5-flash-preview".to_string(),
-                messages: vec![Message {
-                    role: "user".to_string(),
-                    content: format!(
-                        "Explica siguiente paso y da el titulo y hazlo de acuerdo con el prompt y title: '{}' y '{}'. Evita ser redundante. Devuelve el texto en español. Usa markdown simple como listas/bulleted points o negritas. Devuélvelo como un objeto JSON con un campo de 'explanation' que contenga el explicacion. No incluyas ningún otro texto ni explicaciones. No usas newlines y haz el texto corto y conciso. Para mostrar matematicas, usa KaTeX entre $. RECUERDE DEVOLVERLO COMO UN OBJECTO JSON CON UN CAMPO 'explanation' QUE CONTENGA EL EXPLICACION., No usas double quotes, y si tienes que usarlos, escapealos. Si tienes que usar un backslash, incluso con el KaTeX, escapealo. No pones newlines sino \\n",
-                        step_title,
-                        step_prompt_content
-                    ),
-                    name: None,
-                    tool_calls: None,
-                }],
-                stream: None,
-                response_format: serde_json
-                    ::from_value(
-                        json!({
-                    "type": "json_schema",
-                    "json_schema": {
-                        "name": "explanation",
-                        "strict": true,
-                        "schema": {
-                            "type": "object",
-                            "properties": {
-                                "explanation": {"type": "string"}
-                            },
-                            "required": ["explanation"],
-                            "additionalProperties": false
-                        }
-                    }
-                })
-                    )
-                    .ok(),
-                tools: None,
-                provider: None,
-                models: None,
-                transforms: None,
-            };
+/// Lowercases `text` and drops Spanish accents so terms match however they are
+/// capitalized or accented.
+pub fn fold_text(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'á' | 'à' | 'ä' | 'â' => 'a',
+            'é' | 'è' | 'ë' | 'ê' => 'e',
+            'í' | 'ì' | 'ï' | 'î' => 'i',
+            'ó' | 'ò' | 'ö' | 'ô' => 'o',
+            'ú' | 'ù' | 'ü' | 'û' => 'u',
+            c => c,
+        })
+        .collect()
+}
 
-            let text_response = match chat_api.chat_completion(text_request).await {
-                Ok(r) => r,
-                Err(e) => {
-                    info!("Text explanation failed for step {}: {}", i + 1, e);
-                    continue;
-                }
-            };
+fn first_mention(term: &str, steps: &[(usize, &Step)]) -> Option<u32> {
+    let term = fold_text(term);
+    steps
+        .iter()
+        .find(|(_, step)| {
+            fold_text(&step.title).contains(&term) || fold_text(&step.explanation).contains(&term)
+        })
+        .map(|(i, _)| *i as u32)
+}
 
-            let text_content = &text_response.choices[0].message.content
-                .replace("```json", "")
-                .replace("```", "")
-                .replace("\n", "");
+struct StepContext<'a> {
+    lesson: &'a LessonRecord,
+    id: &'a str,
+    llm: &'a Llm,
+    collections: &'a Collections,
+    difficulty: &'a Difficulty,
+    /// The lesson's level as described in prompts, see `LessonRecord::audience_prompt`.
+    level: &'a str,
+    wikipedia_url: &'a Option<String>,
+    wikipedia_images: &'a Option<Vec<String>>,
+    reusable_images: &'a [ReusableImage],
+    /// Wikipedia image file names already screened for this lesson, and whether they
+    /// may be used.
+    screened_images: &'a Mutex<HashMap<String, bool>>,
+}
 
-            let text_json: serde_json::Value = match serde_json::from_str(text_content) {
-                Ok(v) => v,
-                Err(e) => {
-                    info!("Failed to parse text JSON: {}, TEXT: {}", e, text_content);
-                    continue;
-                }
-            };
+/// Maximum number of outline steps generated at the same time, from `STEP_CONCURRENCY`.
+fn step_concurrency() -> usize {
+    match env_number("STEP_CONCURRENCY", 3) {
+        0 => 3,
+        n => n,
+    }
+}
 
-            (None, text_json["explanation"].as_str().unwrap_or_default().to_string())
+/// Most rewrites asked for per step to bring its explanation into the readability
+/// band for the lesson's difficulty, from `READABILITY_REWRITES`; 0 only scores.
+fn readability_rewrites() -> u32 {
+    env_number("READABILITY_REWRITES", 1)
+}
+
+/// Scores `explanation` for `difficulty` and, while it falls outside the target band,
+/// asks the model to rewrite it. Keeps whichever version came closest to the band.
+async fn fit_readability(
+    explanation: String,
+    difficulty: &Difficulty,
+    level: &str,
+    llm: &Llm,
+    models: &mut BTreeMap<String, String>
+) -> (String, Option<Readability>) {
+    let Some(mut best) = readability::assess(&explanation, difficulty) else {
+        return (explanation, None);
+    };
+    let mut best_text = explanation;
+    let (low, high) = readability::target_band(difficulty);
+    let mut rewrites = 0;
+    while !best.in_band && rewrites < readability_rewrites() {
+        rewrites += 1;
+        let direction = if best.score < low {
+            "más fácil de leer: usa frases más cortas y palabras más sencillas y comunes"
+        } else {
+            "más precisa para este nivel: puedes usar frases más largas y vocabulario más técnico"
+        };
+        let target = if high.is_finite() {
+            format!("entre {:.0} y {:.0}", low, high)
+        } else {
+            format!("por encima de {:.0}", low)
+        };
+        let prompt = format!(
+            "Reescribe la siguiente explicación para un nivel {}. Su legibilidad en la escala de Szigriszt-Pazos es {:.0} y debe quedar {}, así que hazla {}. \
+            Mantén todo el contenido, el mismo idioma, el markdown simple y las matemáticas en KaTeX entre $. \
+            Devuélvelo como un objeto JSON con un campo 'explanation'. Solo JSON sin otros textos.\n\n{}",
+            level,
+            best.score,
+            target,
+            direction,
+            best_text
+        );
+        let completion = match
+            llm.complete::<Explanation>(Stage::Rewrite, vec![json!({ "role": "user", "content": prompt })]).await
+        {
+            Ok(completion) => completion,
+            Err(e) => {
+                info!("Readability rewrite failed: {}", e);
+                break;
+            }
+        };
+        let Some(rewritten) = readability::assess(&completion.value.explanation, difficulty) else {
+            continue;
         };
+        if readability::distance(rewritten.score, difficulty) < readability::distance(best.score, difficulty) {
+            models.insert(Stage::Rewrite.as_ref().to_string(), completion.model);
+            best = rewritten;
+            best_text = completion.value.explanation;
+        }
+    }
+    best.rewrites = rewrites;
+    (best_text, Some(best))
+}
 
-        // update step with image and explanation in mongoDB
-
-        // info!(
-        //     "Updating lesson with step {}: image: {:?}, explanation: {}",
-        //     i + 1,
-        //     image,
-        //     explanation
-        // );
-
-        // let result = collections.lessons.update_one(
-        //     doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
-        //     doc! {
-        //             "$set": {
-        //                 format!("steps.{}.image", i): image.clone(),
-        //                 format!("steps.{}.explanation", i): explanation.clone(),
-        //             }
-        //         }
-        // ).await;
-        // if result.is_err() {
-        //     info!("Failed to update lesson with new step: {}", result.err().unwrap());
-        //     continue;
-        // }
+async fn generate_step(
+    i: usize,
+    step: &OutlineItem,
+    context: &StepContext<'_>
+) -> Result<Option<Step>, PipelineError> {
+    let StepContext { id, llm, collections, difficulty, level, wikipedia_url, wikipedia_images, reusable_images, .. } = *context;
+    let step_title = step.title.as_str();
+    let step_prompt_content = step.prompt.as_str();
+    let media_type = step.media_type;
+    let speech = step.speech.as_str();
+    info!("Wikipedia Images: {:?}", wikipedia_images);
+    let mut models = BTreeMap::new();
+    let mut reused_references = Vec::new();
+    let (image, explanation) = if media_type == MediaType::Image {
+        let mut res = None;
+        // Remixes keep the source image for steps that kept their title
+        if let Some(reused) = reusable_images.iter().find(|r| r.title == normalize_title(step_title)) {
+            let stored = match ObjectId::parse_str(&reused.image) {
+                Ok(oid) => collections.images.find_one(doc! { "_id": oid }).await.ok().flatten(),
+                Err(_) => None,
+            };
+            if let Some(stored) = stored {
+                let explanation = match generate_image_explanation(&stored.data, llm).await {
+                    Some(completion) => {
+                        models.insert(Stage::ImageExplanation.as_ref().to_string(), completion.model);
+                        completion.value.explanation
+                    }
+                    None => step_prompt_content.to_string(),
+                };
+                reused_references = reused.references.clone();
+                res = Some((Some(reused.image.clone()), explanation));
+            }
+        }
+        if res.is_none() && let Some(images) = wikipedia_images.clone() {
+            for image in images {
+                // if is_relevant_image(image.clone(), step_title) {
+                if let Some(image_url) = get_image_url(&image).await {
+                    if !image_allowed(&image, i, context).await? {
+                        info!("Skipping image {} for step {}: blocked by moderation", image, i + 1);
+                        continue;
+                    }
+                    let explanation = generate_image_explanation(&image_url, llm).await;
+                    info!("Image URL: {}", image_url);
+                    // download the image as base64 and then store it in MongoDB
+                    let image_response = match HTTP.send(HTTP.get(&image_url)).await {
+                        Ok(r) => r,
+                        Err(e) => {
+                            info!("Image download failed: {}", e);
+                            continue;
+                        }
+                    };
+                    let image_data = match image_response.bytes().await {
+                        Ok(data) => data,
+                        Err(e) => {
+                            info!("Failed to read image data: {}", e);
+                            continue;
+                        }
+                    };
+
+                    // Encode image data to string
+                    let image_data = general_purpose::STANDARD.encode(&image_data);
+
+                    info!("First 50 chars of image data: {:?}", &image_data[0..50]);
+
+                    let image_b64 = format!(
+                        "data:image/{};base64,{}",
+                        image_url.rsplit(".").next().unwrap_or("png"),
+                        &image_data
+                    );
 
-        let references = gather_references(
-            media_type,
-            &explanation,
-            &wikipedia_url,
-            image.as_ref(),
-            &client,
-            &chat_api
-        ).await;
-        let tts_id = "";
-        // let tts_api_key = match std::env::var("ELEVENLABS_API_KEY") {
+                    let explanation = match explanation {
+                        Some(completion) => {
+                            models.insert(Stage::ImageExplanation.as_ref().to_string(), completion.model);
+                            completion.value.explanation
+                        }
+                        None => step_prompt_content.to_string(),
+                    };
+
+                    // upload image to MongoDB
+                    let image_doc = Image {
+                        data: image_b64.clone(),
+                    };
+                    let image_result = collections.images.insert_one(image_doc).await;
+                    if image_result.is_err() {
+                        info!(
+                            "Failed to upload image to MongoDB: {}",
+                            image_result.err().unwrap()
+                        );
+                        continue;
+                    }
+                    let image_id = image_result
+                        .unwrap()
+                        .inserted_id.as_object_id()
+                        .unwrap()
+                        .to_string();
+
+                    res = Some((Some(image_id), explanation));
+                    break;
+                }
+            }
+            // }
+        }
+        // Get OpenAI API key
+        // let openai_key = match std::env::var("OPENAI_API_KEY") {
         //     Ok(k) => k,
-        //     Err(e) => {
-        //         info!("Failed to load ELEVEN LABS API key: {}", e);
-        //         return;
-        //     }
-        // };
-        // let tts_id = {
-        //     // Generate TTS audio for text steps
-        //     let tts_response = reqwest::Client
-        //         ::new()
-        //         .post(format!("https://api.elevenlabs.io/v1/text-to-speech/86V9x9hrQds83qf7zaGn"))
-        //         .query(
-        //             &[
-        //                 ("optimize_streaming_latsency", "0"),
-        //                 ("output_format", "mp3_22050_32"),
-        //             ]
-        //         )
-        //         .header("xi-api-key", &tts_api_key)
-        //         .json(
-        //             &json!({
-        //     "text": speech,
-        //     "voice_settings": {
-        //         "stability": 0.5,
-        //         "similarity_boost": 0.75,
-        //         "style": 0
-        //     },
-        //     "model_id": "eleven_flash_v2_5"
-        // })
-        //         )
-        //         .send().await;
-
-        //     match tts_response {
-        //         Ok(response) => {
-        //             if response.status().is_success() {
-        //                 let audio_data = response.bytes().await.unwrap_or_default();
-
-        //                 match
-        //                     collections.tts.insert_one(TTS {
-        //                         data: (Binary {
-        //                             subtype: BinarySubtype::Generic,
-        //                             bytes: audio_data.to_vec(),
-        //                         }).bytes,
-        //                     }).await
-        //                 {
-        //                     Ok(result) =>
-        //                         Some(result.inserted_id.as_object_id().unwrap().to_string()),
-        //                     Err(e) => {
-        //                         info!("Failed to insert TTS audio: {}", e);
-        //                         None
-        //                     }
-        //                 }
-        //             } else {
-        //                 info!("TTS API request failed with status: {}", response.status());
-        //                 None
-        //             }
-        //         }
-        //         Err(e) => {
-        //             info!("TTS request failed: {}", e);
-        //             None
-        //         }
+        //     Err(_) => {
+        //         info!("Missing OPENAI_API_KEY environment variable");
+        //         continue;
         //     }
         // };
 
-        // Update lesson with tts_id and references
+        // push a value to the steps array at the position i
         // collections.lessons
         //     .update_one(
         //         doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
         //         doc! {
-        //             "$set": {
-        //                 format!("steps.{}.tts", i): tts_id.clone(),
-        //                 format!("steps.{}.references", i): references.clone(),
+        //             "$push": {
+        //                 "steps": {
+        //                     "$each": [{
+        //                         "title": step_title,
+        //                         "explanation": step_prompt_content,
+        //                         "image": Option::<String>::None,
+        //                         "tts": Option::<String>::None,
+        //                         "references": Array::new(),
+        //                     }],
+        //                     "$position": i as i32
+        //                 }
         //             }
         //         }
         //     ).await
         //     .expect("Failed to update lesson with new step");
-        collections.lessons
-            .update_one(
-                doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
-                doc! {
-                    "$push": {
-                        "steps": {
-                            "$each": [{
-                                "title": step_title,
-                                "image": if media_type == "image" {
-                                    Some(image)
-                                } else {
-                                    None
-                                },
-                                "explanation": explanation,
-                                "speech": speech.to_string(),
-                                "tts": tts_id,
-                                "references": references,
-                            }]
-                        }
+
+        // Create HTTP client
+
+        // let image_response = match
+        //     client
+        //         .post("https://api.openai.com/v1/images/generations")
+        //         .header(header::AUTHORIZATION, format!("Bearer {}", openai_key))
+        //         .json(
+        //             &json!({
+        //         "model": "gpt-image-1",
+        //         "prompt": format!("Dada la siguiente explicación, genera una imagen que represente visualmente el contenido de forma clara y coherente. Asegúrate de que la imagen esté relacionada directamente con el tema descrito. Explicación: {}", step_prompt_content),
+        //         "n": 1,
+        //         "size": "1024x1024",
+        //         "quality": "low",
+        //     })
+        //         )
+        //         .send().await
+        // {
+        //     Ok(r) => r,
+        //     Err(e) => {
+        //         info!("File generation failed: {}", e);
+        //         continue;
+        //     }
+        // };
+
+        // // Parse image response
+        // // info!("File response: {:?}", image_response);
+        // let image_json: serde_json::Value = match image_response.json().await {
+        //     Ok(j) => j,
+        //     Err(e) => {
+        //         info!("Failed to parse image response: {}", e);
+        //         continue;
+        //     }
+        // };
+
+        // let mut image_b64 = match image_json["data"][0]["b64_json"].as_str() {
+        //     Some(b64) => b64.to_string(),
+        //     None => {
+        //         info!("Missing base64 image data");
+        //         continue;
+        //     }
+        // };
+
+        // use reqwest to downloa the image
+
+        // image_b64 = "data:image/png;base64,".to_string() + &image_b64;
+        // // Generate explanation using original model
+
+        // // let explanation = generate_image_explanation(image_b64.clone(), api_key.clone()).await;
+        // let explanation = step_prompt_content.to_string();
+
+        // // upload image to MongoDB
+        // let image_doc = Image {
+        //     data: image_b64.clone(),
+        // };
+        // let image_result = collections.images.insert_one(image_doc).await;
+        // if image_result.is_err() {
+        //     info!("Failed to upload image to MongoDB: {}", image_result.err().unwrap());
+        //     continue;
+        // }
+        // let image_id = image_result.unwrap().inserted_id.as_object_id().unwrap().to_string();
+
+        // (Some(image_id), explanation)
+        res.unwrap_or((None, speech.to_string()))
+    } else {
+        // Text-based step
+        let text_prompt = format!(
+            "Explica siguiente paso y da el titulo y hazlo de acuerdo con el prompt y title: '{}' y '{}', a un nivel {}. Evita ser redundante. Devuelve el texto en español. Usa markdown simple como listas/bulleted points o negritas. Devuélvelo como un objeto JSON con un campo de 'explanation' que contenga el explicacion. No incluyas ningún otro texto ni explicaciones. No usas newlines y haz el texto corto y conciso. Para mostrar matematicas, usa KaTeX entre $. RECUERDE DEVOLVERLO COMO UN OBJECTO JSON CON UN CAMPO 'explanation' QUE CONTENGA EL EXPLICACION., No usas double quotes, y si tienes que usarlos, escapealos. Si tienes que usar un backslash, incluso con el KaTeX, escapealo. No pones newlines sino \\n",
+            step_title,
+            step_prompt_content,
+            level
+        );
+
+        // Forward the explanation to the lesson room as it streams in, keeping only the
+        // newest text if the socket falls behind
+        let (partials, mut pending) = mpsc::unbounded_channel::<String>();
+        task::spawn({
+            let id = id.to_string();
+            async move {
+                while let Some(mut explanation) = pending.recv().await {
+                    while let Ok(newer) = pending.try_recv() {
+                        explanation = newer;
+                    }
+                    websocket::emit_step_partial(&id, i, &explanation).await;
+                }
+            }
+        });
+        let mut last_partial = String::new();
+        let mut withheld = false;
+        let text_completion = match
+            llm.complete_streaming::<Explanation>(
+                Stage::StepText,
+                vec![json!({ "role": "user", "content": text_prompt })],
+                |raw| {
+                    if withheld {
+                        return;
+                    }
+                    let partial = structured::partial_string_field(raw, "explanation").unwrap_or_default();
+                    // Stop showing the step once the term lists catch it; the finished
+                    // step is screened in full before it is stored
+                    if MODERATION.blocks(&partial) {
+                        withheld = true;
+                        return;
+                    }
+                    if partial != last_partial {
+                        partials.send(partial.clone()).ok();
+                        last_partial = partial;
                     }
                 }
             ).await
-            .expect("Failed to update lesson with new step");
+        {
+            Ok(c) => c,
+            Err(e) => {
+                info!("Text explanation failed for step {}: {}", i + 1, e);
+                return Ok(None);
+            }
+        };
+
+        models.insert(Stage::StepText.as_ref().to_string(), text_completion.model);
+        (None, text_completion.value.explanation)
+    };
+    let (explanation, readability) = fit_readability(explanation, difficulty, level, llm, &mut models).await;
+
+    // update step with image and explanation in mongoDB
+
+    // info!(
+    //     "Updating lesson with step {}: image: {:?}, explanation: {}",
+    //     i + 1,
+    //     image,
+    //     explanation
+    // );
+
+    // let result = collections.lessons.update_one(
+    //     doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
+    //     doc! {
+    //             "$set": {
+    //                 format!("steps.{}.image", i): image.clone(),
+    //                 format!("steps.{}.explanation", i): explanation.clone(),
+    //             }
+    //         }
+    // ).await;
+    // if result.is_err() {
+    //     info!("Failed to update lesson with new step: {}", result.err().unwrap());
+    //     continue;
+    // }
+
+    let (mut references, references_model) = gather_references(
+        media_type,
+        &explanation,
+        wikipedia_url,
+        image.as_ref(),
+        llm
+    ).await;
+    if let Some(model) = references_model {
+        models.insert(Stage::References.as_ref().to_string(), model);
     }
+    references.extend(reused_references);
+    let tts_id = None;
+    // let tts_api_key = match std::env::var("ELEVENLABS_API_KEY") {
+    //     Ok(k) => k,
+    //     Err(e) => {
+    //         info!("Failed to load ELEVEN LABS API key: {}", e);
+    //         return;
+    //     }
+    // };
+    // let tts_id = {
+    //     // Generate TTS audio for text steps
+    //     let tts_response = reqwest::Client
+    //         ::new()
+    //         .post(format!("https://api.elevenlabs.io/v1/text-to-speech/86V9x9hrQds83qf7zaGn"))
+    //         .query(
+    //             &[
+    //                 ("optimize_streaming_latsency", "0"),
+    //                 ("output_format", "mp3_22050_32"),
+    //             ]
+    //         )
+    //         .header("xi-api-key", &tts_api_key)
+    //         .json(
+    //             &json!({
+    //     "text": speech,
+    //     "voice_settings": {
+    //         "stability": 0.5,
+    //         "similarity_boost": 0.75,
+    //         "style": 0
+    //     },
+    //     "model_id": "eleven_flash_v2_5"
+    // })
+    //         )
+    //         .send().await;
+
+    //     match tts_response {
+    //         Ok(response) => {
+    //             if response.status().is_success() {
+    //                 let audio_data = response.bytes().await.unwrap_or_default();
+
+    //                 match
+    //                     collections.tts.insert_one(TTS {
+    //                         data: (Binary {
+    //                             subtype: BinarySubtype::Generic,
+    //                             bytes: audio_data.to_vec(),
+    //                         }).bytes,
+    //                     }).await
+    //                 {
+    //                     Ok(result) =>
+    //                         Some(result.inserted_id.as_object_id().unwrap().to_string()),
+    //                     Err(e) => {
+    //                         info!("Failed to insert TTS audio: {}", e);
+    //                         None
+    //                     }
+    //                 }
+    //             } else {
+    //                 info!("TTS API request failed with status: {}", response.status());
+    //                 None
+    //             }
+    //         }
+    //         Err(e) => {
+    //             info!("TTS request failed: {}", e);
+    //             None
+    //         }
+    //     }
+    // };
+
+    // Update lesson with tts_id and references
+    // collections.lessons
+    //     .update_one(
+    //         doc! { "_id": ObjectId::parse_str(id.clone()).unwrap() },
+    //         doc! {
+    //             "$set": {
+    //                 format!("steps.{}.tts", i): tts_id.clone(),
+    //                 format!("steps.{}.references", i): references.clone(),
+    //             }
+    //         }
+    //     ).await
+    //     .expect("Failed to update lesson with new step");
+    Ok(Some(Step {
+        title: step_title.to_string(),
+        media_type,
+        image,
+        reading_time: duration::reading_seconds(media_type, &explanation),
+        listening_time: duration::listening_seconds(speech),
+        readability,
+        explanation,
+        speech: speech.to_string(),
+        tts: tts_id,
+        references,
+        models,
+        sub_lessons: Vec::new(),
+    }))
 }
 
 async fn get_wikipedia_reference(
     prompt: &str,
-    client: &ChatApi,
+    llm: &Llm,
     collections: &Collections,
     lesson_id: &str
 ) -> Option<String> {
@@ -623,63 +1207,20 @@ async fn get_wikipedia_reference(
         format!("Dado el tema '{}', proporciona la URL más relevante de la página de Wikipedia en español. Devuelve un objeto JSON con un campo 'wikipedia_url'. Solo devuelve el objeto JSON.
 ", prompt);
 
-    let request = ChatCompletionRequest {
-        model: "google/gemini-2.0-flash-lite-001".to_string(),
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: wiki_prompt,
-            name: None,
-            tool_calls: None,
-        }],
-        response_format: serde_json
-            ::from_value(
-                json!({
-            "type": "json_schema",
-            "json_schema": {
-                "name": "wikipedia_ref",
-                "strict": true,
-                "schema": {
-                    "type": "object",
-                    "properties": {
-                        "wikipedia_url": {"type": "string"}
-                    },
-                    "required": ["wikipedia_url"],
-                    "additionalProperties": false
-                }
-            }
-        })
-            )
-            .ok(),
-        stream: Some(false),
-        tools: None,
-        provider: None,
-        models: None,
-        transforms: None,
-    };
-
-    let response = match client.chat_completion(request).await {
-        Ok(r) => r,
-        Err(_) => {
-            return None;
-        }
-    };
-
-    let raw_content = response.choices[0].message.content.replace("```json", "").replace("```", "");
-
-    let parsed: serde_json::Value = match serde_json::from_str(&raw_content) {
-        Ok(v) => v,
-        Err(_) => {
-            return None;
-        }
-    };
+    let completion = llm
+        .complete::<WikipediaReference>(
+            Stage::WikiLookup,
+            vec![json!({ "role": "user", "content": wiki_prompt })]
+        ).await
+        .ok()?;
 
-    let url = parsed["wikipedia_url"].as_str()?.to_string();
+    let url = completion.value.wikipedia_url;
 
     // Update lesson with Wikipedia URL
     collections.lessons
         .update_one(
             doc! { "_id": ObjectId::parse_str(lesson_id).unwrap() },
-            doc! { "$set": { "wikipedia_url": &url } }
+            doc! { "$set": { "wikipedia_url": &url, "wikipedia_model": completion.model } }
         ).await
         .ok()?;
 
@@ -687,17 +1228,11 @@ async fn get_wikipedia_reference(
 }
 
 async fn get_wikipedia_images(url: &Option<String>) -> Option<Vec<String>> {
-    let page_title = url.as_ref()?.split('/').last()?;
+    let page_title = url.as_ref()?.split('/').next_back()?;
     let api_url =
         format!("https://es.wikipedia.org/w/api.php?action=query&titles={}&prop=images&format=json", page_title);
 
-    let response: serde_json::Value = reqwest::Client
-        ::new()
-        .get(&api_url)
-        .send().await
-        .ok()?
-        .json().await
-        .ok()?;
+    let response: serde_json::Value = HTTP.send(HTTP.get(&api_url)).await.ok()?.json().await.ok()?;
 
     response["query"]["pages"]
         .as_object()?
@@ -711,6 +1246,7 @@ async fn get_wikipedia_images(url: &Option<String>) -> Option<Vec<String>> {
         .into()
 }
 
+#[allow(dead_code)]
 fn is_relevant_image(image_name: String, step_title: &str) -> bool {
     let binding = step_title.to_lowercase();
     let binding = binding.split_whitespace().collect::<Vec<_>>();
@@ -719,7 +1255,6 @@ fn is_relevant_image(image_name: String, step_title: &str) -> bool {
         .to_lowercase()
         .split(&['_', '-'][..])
         .any(|part| binding.iter().any(|term| part.contains(term)))
-        .into()
 }
 
 async fn get_image_url(image_name: &str) -> Option<String> {
@@ -727,13 +1262,7 @@ async fn get_image_url(image_name: &str) -> Option<String> {
     let api_url =
         format!("https://es.wikipedia.org/w/api.php?action=query&titles={}&prop=imageinfo&iiprop=url&format=json", encoded);
 
-    let response: serde_json::Value = reqwest::Client
-        ::new()
-        .get(&api_url)
-        .send().await
-        .ok()?
-        .json().await
-        .ok()?;
+    let response: serde_json::Value = HTTP.send(HTTP.get(&api_url)).await.ok()?.json().await.ok()?;
     info!("Image URL response: {:?}", response);
     response["query"]["pages"]
         .as_object()?
@@ -745,11 +1274,9 @@ async fn get_image_url(image_name: &str) -> Option<String> {
         .map(|s| s.to_string())
 }
 
-async fn generate_image_explanation(image_url: String, api_key: String) -> String {
-    let request_body =
+async fn generate_image_explanation(image_url: &str, llm: &Llm) -> Option<Completion<Explanation>> {
+    let message =
         json!({
-    "model": "google/gemini-2.5-flash-preview",
-    "messages": [{
         "role": "user",
         "content": [
             {
@@ -763,104 +1290,55 @@ async fn generate_image_explanation(image_url: String, api_key: String) -> Strin
                 }
             }
         ]
-    }],
-    "response_format": {
-        "type": "json_schema",
-        "json_schema": {
-            "name": "explanation",
-            "strict": true,
-            "schema": {
-                "type": "object",
-                "properties": {
-                    "explanation": {"type": "string"}
-                },
-                "required": ["explanation"],
-                "additionalProperties": false
-            }
-        }
-    }
-});
-
-    let client = reqwest::Client::new();
+    });
 
-    let response = match
-        client
-            .post("https://openrouter.ai/api/v1/chat/completions")
-            .header(header::AUTHORIZATION, format!("Bearer {}", api_key))
-            .json(&request_body)
-            .send().await
+    match
+        llm.complete::<Explanation>(Stage::ImageExplanation, vec![message]).await
     {
-        Ok(r) => r,
+        Ok(completion) => Some(completion),
         Err(e) => {
             info!("Explanation request failed: {}", e);
-            return "".to_string();
-        }
-    };
-    let response_json: serde_json::Value = match response.json().await {
-        Ok(j) => j,
-        Err(e) => {
-            info!("Failed to parse explanation response: {}", e);
-            return "".to_string();
-        }
-    };
-
-    let explanation = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or_default()
-        .to_string();
-
-    let explanation_json: serde_json::Value = match
-        serde_json::from_str(&explanation.replace("```json", "").replace("```", ""))
-    {
-        Ok(v) => v,
-        Err(e) => {
-            info!("Failed to parse explanation JSON: {}", e);
-            return "".to_string();
+            None
         }
-    };
-    explanation_json["explanation"].as_str().unwrap_or("No explanation generated").to_string()
+    }
 }
+
 async fn gather_references(
-    media_type: &str,
+    media_type: MediaType,
     explanation: &str,
     wikipedia_url: &Option<String>,
     image_url: Option<&String>,
-    http_client: &reqwest::Client,
-    chat_api: &ChatApi
-) -> Vec<String> {
+    llm: &Llm
+) -> (Vec<String>, Option<String>) {
     let mut references = Vec::new();
+    let mut model = None;
 
     match media_type {
-        "image" => {
-            if let Some(url) = image_url {
-                if url.starts_with("http") {
-                    references.push(url.to_string());
-                }
+        MediaType::Image => {
+            if let Some(url) = image_url && url.starts_with("http") {
+                references.push(url.to_string());
             }
         }
-        "text" => {
-            if let Some(url) = wikipedia_url {
-                if let Some(page_content) = fetch_wikipedia_content(url, http_client).await {
-                    let ai_references = analyze_content_with_ai(
-                        explanation.into(),
-                        page_content,
-                        chat_api
-                    ).await;
-                    references.extend(ai_references);
-                }
+        MediaType::Text => {
+            if
+                let Some(url) = wikipedia_url &&
+                let Some(page_content) = fetch_wikipedia_content(url).await &&
+                let Some(completion) = analyze_content_with_ai(explanation, page_content, llm).await
+            {
+                references.extend(completion.value.references);
+                model = Some(completion.model);
             }
         }
-        _ => {}
     }
 
-    references
+    (references, model)
 }
 
-async fn fetch_wikipedia_content(url: &str, client: &reqwest::Client) -> Option<String> {
-    let page_title = url.split('/').last()?;
+pub async fn fetch_wikipedia_content(url: &str) -> Option<String> {
+    let page_title = url.split('/').next_back()?;
     let api_url = format!("https://es.wikipedia.org/w/rest.php/v1/page/{}", page_title);
 
-    match client.get(&api_url).header("Accept", "application/json").send().await {
+    match HTTP.send(HTTP.get(&api_url).header("Accept", "application/json")).await {
         Ok(response) => {
             let page_data: serde_json::Value = response.json().await.ok()?;
             page_data["source"].as_str().map(|s| s.to_string())
@@ -873,12 +1351,12 @@ async fn fetch_wikipedia_content(url: &str, client: &reqwest::Client) -> Option<
 }
 
 async fn analyze_content_with_ai(
-    explanation: String,
+    explanation: &str,
     content: String,
-    client: &ChatApi
-) -> Vec<String> {
+    llm: &Llm
+) -> Option<Completion<References>> {
     // Truncate content to fit model context window
-    let truncated = truncate_content(content, 10000);
+    let truncated = truncate_content(&content, 10000);
 
     let prompt = format!(
         "Analiza este contenido de Wikipedia e identifica las referencias relevantes para la explicación. \
@@ -887,76 +1365,23 @@ Devuelve un objeto JSON con un arreglo 'references' que contenga URLs. Máximo 3
         truncated
     );
 
-    let request = ChatCompletionRequest {
-        model: "google/gemini-2.0-flash-lite-001".to_string(),
-        messages: vec![Message {
-            role: "user".to_string(),
-            content: prompt,
-            name: None,
-            tool_calls: None,
-        }],
-        response_format: serde_json
-            ::from_value(
-                json!({
-            "type": "json_schema",
-            "json_schema": {
-                "name": "references",
-                "strict": true,
-                "schema": {
-                    "type": "object",
-                    "properties": {
-                        "references": {
-                            "type": "array",
-                            "items": {"type": "string"}
-                        }
-                    },
-                    "required": ["references"],
-                    "additionalProperties": false
-                }
-            }
-        })
-            )
-            .ok(),
-        stream: Some(false),
-        tools: None,
-        provider: None,
-        models: None,
-        transforms: None,
-    };
-
-    match client.chat_completion(request).await {
-        Ok(response) => {
-            let raw = response.choices[0].message.content.replace("```json", "").replace("```", "");
-
-            serde_json
-                ::from_str::<serde_json::Value>(&raw)
-                .ok()
-                .and_then(|v|
-                    v["references"].as_array().map(|arr|
-                        arr
-                            .iter()
-                            .filter_map(|v| v.as_str().map(String::from))
-                            .collect()
-                    )
-                )
-                .unwrap_or_default()
-        }
+    match
+        llm.complete::<References>(
+            Stage::References,
+            vec![json!({ "role": "user", "content": prompt })]
+        ).await
+    {
+        Ok(completion) => Some(completion),
         Err(e) => {
             info!("AI reference analysis failed: {}", e);
-            Vec::new()
+            None
         }
     }
 }
 
-fn truncate_content(content: String, max_chars: usize) -> String {
-    let len = content
-        .char_indices()
-        .rev()
-        .nth(max_chars - 1)
-        .map_or(0, |(idx, _ch)| idx);
-    let trunicated = content
-        .clone()
-        .drain(0..len)
-        .for_each(|_| {});
-    return format!("{:?}", trunicated);
+pub fn truncate_content(content: &str, max_chars: usize) -> &str {
+    match content.char_indices().nth(max_chars) {
+        Some((idx, _)) => &content[..idx],
+        None => content,
+    }
 }
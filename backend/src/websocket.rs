@@ -1,31 +1,169 @@
-use std::sync::Arc;
+use std::sync::{ Arc, OnceLock };
 use mongodb::bson::{ doc, oid::ObjectId };
-use socketioxide::extract::{ AckSender, Data, SocketRef };
+use serde_json::json;
+use socketioxide::{ extract::{ AckSender, Data, SocketRef }, SocketIo };
 use tracing::info;
 
-use crate::{ types::WebSocketEvents, utils::Collections };
+use crate::{
+    auth::{ parse_user_id, Identity },
+    chat,
+    moderation,
+    progress,
+    routes,
+    types::{ Public, SocketProgress, SocketQuestion, StatusCodes, UserProgress, WebSocketEvents },
+    utils::Collections,
+};
+
+/// Handle used by background tasks to emit to lesson rooms, set once at startup.
+pub static IO: OnceLock<SocketIo> = OnceLock::new();
+
+/// Tells everyone watching a lesson that the step at `index` finished, successfully
+/// or not, and how many of the `total` steps are done.
+pub async fn emit_lesson_progress(
+    lesson_id: &str,
+    index: usize,
+    completed: usize,
+    total: usize,
+    success: bool
+) {
+    let Some(io) = IO.get() else {
+        return;
+    };
+    io.to(lesson_id.to_string())
+        .emit(
+            WebSocketEvents::LessonProgress.as_ref(),
+            &json!({
+                "id": lesson_id,
+                "step": index,
+                "success": success,
+                "completed": completed,
+                "total": total,
+            })
+        ).await
+        .ok();
+}
+
+/// Sends the explanation of the step at `index` as generated so far to everyone
+/// watching the lesson. The stored step arrives later through `update_lesson_data`.
+pub async fn emit_step_partial(lesson_id: &str, index: usize, explanation: &str) {
+    let Some(io) = IO.get() else {
+        return;
+    };
+    io.to(lesson_id.to_string())
+        .emit(
+            WebSocketEvents::StepPartial.as_ref(),
+            &json!({
+                "id": lesson_id,
+                "step": index,
+                "explanation": explanation,
+            })
+        ).await
+        .ok();
+}
+
+/// Who is on the other end of `socket`, from the `X-User-Id` header and address of
+/// its handshake. Ids sent in event payloads are not trusted.
+fn socket_identity(socket: &SocketRef) -> Identity {
+    let parts = socket.req_parts();
+    let user = parts.headers
+        .get("x-user-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_user_id);
+    Identity::from_parts(user, parts)
+}
+
+/// Room joined by every socket of a user, across their devices.
+fn user_room(user: &str) -> String {
+    format!("user:{}", user)
+}
+
+/// Sends `user`'s updated progress on a lesson to all of their sockets.
+pub async fn emit_progress(user: &str, progress: &UserProgress) {
+    let Some(io) = IO.get() else {
+        return;
+    };
+    io.to(user_room(user)).emit(WebSocketEvents::ProgressUpdated.as_ref(), progress).await.ok();
+}
 
 pub fn on_connect(socket: SocketRef, collections: Arc<Collections>) {
     info!("Client connected");
     socket.emit(WebSocketEvents::UpdateLessonData.as_ref(), &0).ok();
-    socket.on(
-        WebSocketEvents::RequestLessonData.as_ref(),
+    socket.on(WebSocketEvents::RequestLessonData.as_ref(), {
+        let collections = Arc::clone(&collections);
         move |socket: SocketRef, Data::<String>(id), ack: AckSender| async move {
             info!(id = id.as_str(), "Received lesson data");
             let socket_id = socket.id.to_string();
             info!("Socket {} joined lesson {}", socket_id, id);
+            let Ok(oid) = ObjectId::parse_str(&id) else {
+                ack.send(&json!({ "status": StatusCodes::InvalidID })).ok();
+                return;
+            };
             socket.join(id.clone());
-            let lesson = collections.lessons
-                .find_one(doc! { "_id": ObjectId::parse_str(id).unwrap() }).await
-                .unwrap();
-            if lesson.is_none() {
-                ack.send("").ok();
+            match collections.lessons.find_one(doc! { "_id": oid }).await {
+                Ok(Some(lesson)) => ack.send(&Public(&lesson)).ok(),
+                _ => ack.send("").ok(),
+            };
+        }
+    });
+    socket.on(WebSocketEvents::CancelLesson.as_ref(), {
+        let collections = Arc::clone(&collections);
+        move |socket: SocketRef, Data::<String>(id), ack: AckSender| async move {
+            info!(id = id.as_str(), "Received lesson cancellation");
+            let status = routes::lessons::cancel_lesson(&id, &socket_identity(&socket), &collections).await;
+            ack.send(&json!({ "status": status })).ok();
+        }
+    });
+    socket.on(WebSocketEvents::AskQuestion.as_ref(), {
+        let collections = Arc::clone(&collections);
+        move |socket: SocketRef, Data::<SocketQuestion>(data), ack: AckSender| async move {
+            let parts = socket.req_parts();
+            if !chat::QUESTION_LIMIT.allow(&parts.headers, &parts.extensions) {
+                ack.send(&json!({ "status": StatusCodes::RateLimited })).ok();
+                return;
+            }
+            let identity = socket_identity(&socket);
+            if let Err(status) = moderation::check_banned(&identity, &collections).await {
+                ack.send(&json!({ "status": status })).ok();
                 return;
-            } else {
-                ack.send(&lesson.unwrap()).ok();
             }
+            let id = data.id.clone();
+            // Tokens only go to the socket that asked; the final answer comes in the ack
+            let on_token = |token: &str| {
+                socket.emit(WebSocketEvents::QuestionToken.as_ref(), &json!({ "id": id, "token": token })).ok();
+            };
+            let result = chat::answer_question(&data.id, &identity, data.question, &collections, on_token).await;
+            match result {
+                Ok(answer) => ack.send(&json!({ "status": StatusCodes::Success, "answer": answer })).ok(),
+                Err(status) => ack.send(&json!({ "status": status })).ok(),
+            };
+        }
+    });
+    socket.on(
+        WebSocketEvents::WatchProgress.as_ref(),
+        |socket: SocketRef, ack: AckSender| {
+            match socket_identity(&socket).user {
+                Some(user) => {
+                    socket.join(user_room(&user));
+                    ack.send(&json!({ "status": StatusCodes::Success })).ok()
+                }
+                None => ack.send(&json!({ "status": StatusCodes::UserNotFound })).ok(),
+            };
         }
     );
+    socket.on(WebSocketEvents::UpdateProgress.as_ref(), {
+        let collections = Arc::clone(&collections);
+        move |socket: SocketRef, Data::<SocketProgress>(data), ack: AckSender| async move {
+            let Some(user) = socket_identity(&socket).user else {
+                ack.send(&json!({ "status": StatusCodes::UserNotFound })).ok();
+                return;
+            };
+            socket.join(user_room(&user));
+            match progress::record_progress(&data.id, &user, data.update, &collections).await {
+                Ok(progress) => ack.send(&json!({ "status": StatusCodes::Success, "progress": progress })).ok(),
+                Err(status) => ack.send(&json!({ "status": status })).ok(),
+            };
+        }
+    });
     socket.on_disconnect(move |socket: SocketRef| {
         info!("Client disconnected {}", socket.id.to_string());
         let socket_id = socket.id.to_string();
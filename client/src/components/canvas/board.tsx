@@ -11,6 +11,7 @@ import {
   Pause,
 } from "lucide-react";
 import { Markdown } from "@/components/ui/markdown";
+import { readySteps } from "@/lib/utils";
 
 interface BoardProps {
   lesson: CanvasData;
@@ -21,7 +22,7 @@ export function Board({ lesson }: BoardProps) {
   const [isPlaying, setIsPlaying] = useState(false);
   const audioRef = useRef<HTMLAudioElement | null>(null);
 
-  const steps = lesson.steps || [];
+  const steps = readySteps(lesson.steps);
 
   const playAudio = useCallback(
     async (index: number) => {
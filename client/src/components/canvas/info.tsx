@@ -3,6 +3,7 @@ import { useState } from "react";
 import { motion, AnimatePresence } from "motion/react";
 import { Button } from "@/components/ui/button";
 import { Board } from "@/components/canvas/board";
+import { readySteps } from "@/lib/utils";
 import {
   Check,
   Loader2,
@@ -26,7 +27,8 @@ export function Info({ lesson }: InfoProps) {
   const [isSideOpen, setIsSideOpen] = useState(true);
   const [expandedItem, setExpandedItem] = useState<string | null>(null);
 
-  const lastProcessedStep = lesson.steps?.length ? lesson.steps.length : -1;
+  const ready = readySteps(lesson.steps);
+  const lastProcessedStep = ready.length ? ready.length : -1;
 
   const handleItemClick = (title: string | undefined) => {
     if (title) {
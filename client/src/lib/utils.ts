@@ -1,6 +1,16 @@
 import { clsx, type ClassValue } from "clsx";
 import { twMerge } from "tailwind-merge";
 
+// Steps are generated out of order, so only show the ones ready from the start
+export function readySteps<T>(steps?: (T | null)[]): T[] {
+  const ready: T[] = [];
+  for (const step of steps || []) {
+    if (!step) break;
+    ready.push(step);
+  }
+  return ready;
+}
+
 export function cn(...inputs: ClassValue[]) {
   return twMerge(clsx(inputs));
 }
@@ -20,14 +20,14 @@ export interface CanvasData {
     media_type?: "text" | "image";
     prompt?: string;
   }[];
-  steps?: {
+  steps?: ({
     title?: string;
     image?: string | null;
     explanation?: string;
     speech?: string;
-    tts?: string;
+    tts?: string | null;
     references?: string[];
-  }[];
+  } | null)[];
   wikipedia_url?: string;
 }
 